@@ -1,13 +1,291 @@
-use cusf_sidechain_types::ADDRESS_LENGTH;
-use miette::Result;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use cusf_sidechain_types::{Hashable, Transaction, ADDRESS_LENGTH, HASH_LENGTH};
+use ed25519_dalek::{Signature, VerifyingKey};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
-#[derive(Serialize, Deserialize)]
-struct Authorization {}
+use crate::state::error::{BlockError, TransactionError};
+use crate::withdrawal::WithdrawalDestination;
 
-fn is_authorized(
-    authorizations: &[Authorization],
+/// Number of transactions whose signatures are verified together in one batch.
+const BATCH_SIZE: usize = 128;
+
+/// Signature over a transaction hash, authorizing the spending of a single input.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Authorization {
+    pub verifying_key: VerifyingKey,
+    pub signature: Signature,
+}
+
+impl Authorization {
+    pub fn get_address(&self) -> [u8; ADDRESS_LENGTH] {
+        get_address(&self.verifying_key)
+    }
+}
+
+/// Output of a transaction that is referred to by the transaction's hash.
+///
+/// `OutPoint::Regular` refers to a transaction by its number, which is only assigned when the
+/// transaction is included in a block, so outputs of mempool transactions can only be spent this
+/// way.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct UnconfirmedOutPoint {
+    pub transaction_hash: [u8; HASH_LENGTH],
+    pub output_number: u8,
+}
+
+impl fmt::Display for UnconfirmedOutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            hex::encode(self.transaction_hash),
+            self.output_number
+        )
+    }
+}
+
+/// Transaction together with one authorization per input, followed by one per unconfirmed input,
+/// in the same order as the inputs.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthorizedTransaction {
+    pub transaction: Transaction,
+    /// Mainchain destinations of the withdrawal outputs, in output order, see
+    /// [`crate::withdrawal::check_withdrawal_destinations`].
+    ///
+    /// Empty if every withdrawal pays out to P2PKH of its `main_address`.
+    pub withdrawal_destinations: Vec<WithdrawalDestination>,
+    /// Inputs spending outputs of transactions that may not be in a block yet.
+    ///
+    /// They are spent after `transaction.inputs`, and stay valid once their transactions are
+    /// included in a block.
+    pub unconfirmed_inputs: Vec<UnconfirmedOutPoint>,
+    pub authorizations: Vec<Authorization>,
+}
+
+impl AuthorizedTransaction {
+    /// Hash that authorizations sign, and that identifies the transaction in blocks, the archive
+    /// and the mempool.
+    ///
+    /// Covers everything but the authorizations. Fields are only hashed if they aren't empty, so a
+    /// transaction hashes the same as it did before the field was added, and a transaction with
+    /// neither withdrawal destinations nor unconfirmed inputs hashes like the plain transaction.
+    pub fn hash(&self) -> [u8; HASH_LENGTH] {
+        let bytes = if !self.unconfirmed_inputs.is_empty() {
+            bincode::serialize(&(
+                &self.transaction,
+                &self.withdrawal_destinations,
+                &self.unconfirmed_inputs,
+            ))
+        } else if !self.withdrawal_destinations.is_empty() {
+            bincode::serialize(&(&self.transaction, &self.withdrawal_destinations))
+        } else {
+            return self.transaction.hash();
+        }
+        .expect("failed to serialize transaction for hashing");
+        blake3::hash(&bytes).into()
+    }
+
+    /// Number of inputs, including unconfirmed inputs, which is also the number of authorizations.
+    pub fn inputs_len(&self) -> usize {
+        self.transaction.inputs.len() + self.unconfirmed_inputs.len()
+    }
+}
+
+/// Address is the first ADDRESS_LENGTH bytes of the blake3 hash of the verifying key.
+pub fn get_address(verifying_key: &VerifyingKey) -> [u8; ADDRESS_LENGTH] {
+    let hash = blake3::hash(verifying_key.as_bytes());
+    let mut address = [0; ADDRESS_LENGTH];
+    address.copy_from_slice(&hash.as_bytes()[..ADDRESS_LENGTH]);
+    address
+}
+
+/// Checks that every input of the transaction is signed by the key its address was derived from.
+///
+/// Signatures are verified strictly, and only accepted if [`verify_authorizations`] would accept
+/// them in a batch too.
+///
+/// `addresses` are the addresses of the spent outputs, in the same order as the authorizations.
+pub fn is_authorized(
+    transaction: &AuthorizedTransaction,
     addresses: &[[u8; ADDRESS_LENGTH]],
-) -> Result<()> {
-    todo!();
+) -> Result<(), TransactionError> {
+    let inputs_len = transaction.inputs_len();
+    let authorizations = &transaction.authorizations;
+    if authorizations.len() != inputs_len || addresses.len() != inputs_len {
+        return Err(TransactionError::WrongAuthorizationCount {
+            inputs: inputs_len,
+            authorizations: authorizations.len(),
+        });
+    }
+    let transaction_hash = transaction.hash();
+    let inputs = &transaction.transaction.inputs;
+    let (authorizations, unconfirmed_authorizations) = authorizations.split_at(inputs.len());
+    let (addresses, unconfirmed_addresses) = addresses.split_at(inputs.len());
+    for ((input, authorization), address) in inputs.iter().zip(authorizations).zip(addresses) {
+        if authorization.get_address() != *address {
+            return Err(TransactionError::WrongKey(input.clone()));
+        }
+        if !verify_strict(authorization, &transaction_hash) {
+            return Err(TransactionError::BadSignature(input.clone()));
+        }
+    }
+    let unconfirmed_inputs = transaction
+        .unconfirmed_inputs
+        .iter()
+        .zip(unconfirmed_authorizations)
+        .zip(unconfirmed_addresses);
+    for ((input, authorization), address) in unconfirmed_inputs {
+        if authorization.get_address() != *address {
+            return Err(TransactionError::WrongUnconfirmedKey(input.clone()));
+        }
+        if !verify_strict(authorization, &transaction_hash) {
+            return Err(TransactionError::BadUnconfirmedSignature(input.clone()));
+        }
+    }
+    Ok(())
+}
+
+fn verify_strict(authorization: &Authorization, transaction_hash: &[u8; HASH_LENGTH]) -> bool {
+    is_batchable(authorization)
+        && authorization
+            .verifying_key
+            .verify_strict(transaction_hash, &authorization.signature)
+            .is_ok()
+}
+
+/// Checks authorizations of all transactions in a block, like [`is_authorized`].
+///
+/// Signatures are verified in parallel batches. If a batch fails, its transactions are verified
+/// one by one to find the transaction that is at fault.
+///
+/// `addresses` are the addresses of the spent outputs of all transactions, in order.
+pub fn verify_authorizations(
+    transactions: &[AuthorizedTransaction],
+    addresses: &[[u8; ADDRESS_LENGTH]],
+) -> Result<(), BlockError> {
+    let mut items = vec![];
+    let mut addresses = addresses;
+    for (index, transaction) in transactions.iter().enumerate() {
+        let inputs_len = transaction.inputs_len().min(addresses.len());
+        let (transaction_addresses, rest) = addresses.split_at(inputs_len);
+        addresses = rest;
+        items.push((index, transaction, transaction_addresses));
+    }
+    items.par_chunks(BATCH_SIZE).try_for_each(|batch| {
+        if verify_batch(batch) {
+            return Ok(());
+        }
+        for (index, transaction, addresses) in batch {
+            is_authorized(transaction, addresses).map_err(|source| {
+                BlockError::InvalidTransaction {
+                    index: *index,
+                    source,
+                }
+            })?;
+        }
+        Ok(())
+    })
+}
+
+/// Returns true if every authorization in the batch is valid.
+fn verify_batch(batch: &[(usize, &AuthorizedTransaction, &[[u8; ADDRESS_LENGTH]])]) -> bool {
+    let transaction_hashes: Vec<_> = batch
+        .iter()
+        .map(|(_, transaction, _)| transaction.hash())
+        .collect();
+    let mut messages: Vec<&[u8]> = vec![];
+    let mut signatures = vec![];
+    let mut verifying_keys = vec![];
+    for ((_, transaction, addresses), transaction_hash) in batch.iter().zip(&transaction_hashes) {
+        let authorizations = &transaction.authorizations;
+        let inputs_len = transaction.inputs_len();
+        if authorizations.len() != inputs_len || addresses.len() != inputs_len {
+            return false;
+        }
+        for (authorization, address) in authorizations.iter().zip(*addresses) {
+            if authorization.get_address() != *address || !is_batchable(authorization) {
+                return false;
+            }
+            messages.push(transaction_hash);
+            signatures.push(authorization.signature);
+            verifying_keys.push(authorization.verifying_key);
+        }
+    }
+    if signatures.is_empty() {
+        return true;
+    }
+    ed25519_dalek::verify_batch(&messages, &signatures, &verifying_keys).is_ok()
+}
+
+/// Checks that batch and single verification agree on the authorization.
+///
+/// `ed25519_dalek::verify_batch` decompresses R, while single verification compares its encoding,
+/// so R must be canonically encoded. Both equations are cofactorless, and batch verification
+/// multiplies them by random scalars, so a small order or torsion component in the key or R could
+/// make a batch accept or reject the same signature depending on the randomness. Requiring both
+/// points to be in the prime order subgroup makes every node reach the same verdict either way.
+fn is_batchable(authorization: &Authorization) -> bool {
+    let verifying_key = CompressedEdwardsY(authorization.verifying_key.to_bytes()).decompress();
+    let r_bytes = authorization.signature.r_bytes();
+    let r = CompressedEdwardsY(*r_bytes).decompress();
+    let (Some(verifying_key), Some(r)) = (verifying_key, r) else {
+        return false;
+    };
+    r.compress().as_bytes() == r_bytes && is_prime_order(&verifying_key) && is_prime_order(&r)
+}
+
+fn is_prime_order(point: &EdwardsPoint) -> bool {
+    !point.is_small_order() && point.is_torsion_free()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{authorize, unsigned};
+    use cusf_sidechain_types::OutPoint;
+    use ed25519_dalek::{SigningKey, Verifier};
+
+    fn transaction() -> Transaction {
+        Transaction {
+            inputs: vec![OutPoint::Deposit { sequence_number: 0 }],
+            outputs: vec![],
+        }
+    }
+
+    #[test]
+    fn single_and_batch_verification_accept_valid_signatures() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let transaction = authorize(&signing_key, transaction(), vec![]);
+        let address = get_address(&signing_key.verifying_key());
+        assert!(is_authorized(&transaction, &[address]).is_ok());
+        assert!(verify_authorizations(&[transaction], &[address]).is_ok());
+    }
+
+    #[test]
+    fn single_and_batch_verification_reject_weak_keys() {
+        // The identity point as key, with the identity as R and a zero s, satisfies the
+        // verification equation for every message.
+        let mut identity = [0; 32];
+        identity[0] = 1;
+        let verifying_key = VerifyingKey::from_bytes(&identity).unwrap();
+        let mut signature_bytes = [0; 64];
+        signature_bytes[..32].copy_from_slice(&identity);
+        let signature = Signature::from_bytes(&signature_bytes);
+        assert!(verifying_key
+            .verify(&transaction().hash(), &signature)
+            .is_ok());
+        let mut transaction = unsigned(transaction());
+        transaction.authorizations = vec![Authorization {
+            verifying_key,
+            signature,
+        }];
+        let address = get_address(&verifying_key);
+        assert!(matches!(
+            is_authorized(&transaction, &[address]),
+            Err(TransactionError::BadSignature(_))
+        ));
+        assert!(verify_authorizations(&[transaction], &[address]).is_err());
+    }
 }
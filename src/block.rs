@@ -0,0 +1,111 @@
+use cusf_sidechain_types::{Output, HASH_LENGTH};
+use rs_merkle::{MerkleProof, MerkleTree};
+use serde::{Deserialize, Serialize};
+
+use crate::authorization::AuthorizedTransaction;
+
+/// Sidechain block header.
+///
+/// Used instead of `cusf_sidechain_types::Header`, which doesn't commit to the block body, so a
+/// BMM'd header could be paired with any coinbase and transactions.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub prev_side_block_hash: [u8; HASH_LENGTH],
+    /// Root of the merkle tree computed by [`compute_merkle_root`].
+    pub merkle_root: [u8; HASH_LENGTH],
+}
+
+impl Header {
+    /// Block hash, which is what gets BMM'd.
+    pub fn hash(&self) -> [u8; HASH_LENGTH] {
+        hash(self)
+    }
+}
+
+/// Everything a light client needs to check that a transaction was included in a block, see
+/// [`verify_transaction_proof`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionProof {
+    pub block_number: u32,
+    pub header: Header,
+    /// Index of the transaction in the block.
+    pub index: usize,
+    /// Number of merkle leaves, the coinbase included.
+    pub leaves_count: usize,
+    /// Serialized rs_merkle proof.
+    pub proof: Vec<u8>,
+}
+
+/// rs_merkle hasher using blake3, like the rest of the sidechain.
+#[derive(Clone)]
+pub struct Blake3;
+
+impl rs_merkle::Hasher for Blake3 {
+    type Hash = [u8; HASH_LENGTH];
+
+    fn hash(data: &[u8]) -> Self::Hash {
+        blake3::hash(data).into()
+    }
+}
+
+/// Merkle leaves are the hash of the whole coinbase, followed by the hashes of the transactions in
+/// block order.
+///
+/// Authorizations aren't committed to, so a transaction is identified by the same hash as
+/// the one it is signed over, see [`AuthorizedTransaction::hash`].
+fn get_merkle_leaves(
+    coinbase: &[Output],
+    transactions: &[AuthorizedTransaction],
+) -> Vec<[u8; HASH_LENGTH]> {
+    let mut leaves = vec![hash(&coinbase)];
+    for transaction in transactions {
+        leaves.push(transaction.hash());
+    }
+    leaves
+}
+
+pub fn compute_merkle_root(
+    coinbase: &[Output],
+    transactions: &[AuthorizedTransaction],
+) -> [u8; HASH_LENGTH] {
+    let leaves = get_merkle_leaves(coinbase, transactions);
+    // There is always at least the coinbase leaf, so the tree always has a root.
+    MerkleTree::<Blake3>::from_leaves(&leaves)
+        .root()
+        .unwrap_or_default()
+}
+
+/// Inclusion proof for the transaction at `index` in the block. The proven leaf is at `index + 1`,
+/// since the coinbase comes first.
+pub fn get_merkle_proof(
+    coinbase: &[Output],
+    transactions: &[AuthorizedTransaction],
+    index: usize,
+) -> MerkleProof<Blake3> {
+    let leaves = get_merkle_leaves(coinbase, transactions);
+    MerkleTree::<Blake3>::from_leaves(&leaves).proof(&[index + 1])
+}
+
+/// Checks that the transaction with `transaction_hash` is committed to by the header of the proof.
+///
+/// This doesn't check that the header is BMM'd, or that it is part of the chain, which the caller
+/// must do separately.
+pub fn verify_transaction_proof(
+    proof: &TransactionProof,
+    transaction_hash: &[u8; HASH_LENGTH],
+) -> bool {
+    let Ok(merkle_proof) = MerkleProof::<Blake3>::from_bytes(&proof.proof) else {
+        return false;
+    };
+    merkle_proof.verify(
+        proof.header.merkle_root,
+        &[proof.index + 1],
+        &[*transaction_hash],
+        proof.leaves_count,
+    )
+}
+
+fn hash<T: Serialize>(value: &T) -> [u8; HASH_LENGTH] {
+    let bytes = bincode::serialize(value).expect("failed to serialize value for hashing");
+    blake3::hash(&bytes).into()
+}
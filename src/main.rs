@@ -1,12 +1,17 @@
 mod authorization;
+mod block;
 mod net;
 mod node;
 mod server;
 mod state;
+#[cfg(test)]
+mod test_utils;
+mod withdrawal;
 
 use cusf_sidechain_proto::sidechain::sidechain_server::SidechainServer;
 use miette::{miette, IntoDiagnostic, Result};
 use node::Node;
+use server::sidechain_extensions::sidechain_extensions_server::SidechainExtensionsServer;
 use tonic::transport::Server;
 
 #[tokio::main]
@@ -22,7 +27,8 @@ async fn main() -> Result<()> {
     let addr = "[::1]:50052".parse().into_diagnostic()?;
     println!("Listening for gRPC on {addr}");
     Server::builder()
-        .add_service(SidechainServer::new(plain))
+        .add_service(SidechainServer::new(plain.clone()))
+        .add_service(SidechainExtensionsServer::new(plain))
         .serve(addr)
         .await
         .into_diagnostic()?;
@@ -2,15 +2,15 @@ use bip300301_enforcer_proto::validator::{
     validator_client::ValidatorClient, GetDepositsRequest, GetMainBlockHeightRequest,
     GetMainChainTipRequest, GetMainChainTipResponse,
 };
-use cusf_sidechain_types::{
-    Hashable, Header, MainBlock, OutPoint, Output, Transaction, HASH_LENGTH,
-};
+use cusf_sidechain_types::{MainBlock, OutPoint, Output, HASH_LENGTH};
 use miette::{IntoDiagnostic, Result};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, path::Path};
 use tonic::transport::Channel;
 
-use crate::state::State;
+use crate::authorization::AuthorizedTransaction;
+use crate::block::{Header, TransactionProof};
+use crate::state::{BundlePreview, State, WithdrawalTransition};
 
 #[derive(Clone)]
 pub struct Node {
@@ -37,7 +37,7 @@ impl Node {
         self.state.is_clean()
     }
 
-    pub async fn collect_transactions(&self) -> Result<Vec<Transaction>> {
+    pub async fn collect_transactions(&self) -> Result<Vec<AuthorizedTransaction>> {
         let transactions = self.state.collect_transactions()?;
         Ok(transactions)
     }
@@ -61,16 +61,56 @@ impl Node {
         self.state.get_withdrawal_bundle()
     }
 
-    pub fn submit_transaction(&self, transaction: &Transaction) -> Result<()> {
+    /// Read only counterpart of `get_withdrawal_bundle`.
+    pub fn preview_withdrawal_bundle(&self) -> Result<BundlePreview> {
+        self.state.preview_withdrawal_bundle()
+    }
+
+    /// Returns the transitions of a withdrawal, oldest first.
+    pub fn get_withdrawal_status(
+        &self,
+        outpoint: &OutPoint,
+    ) -> Result<Option<Vec<WithdrawalTransition>>> {
+        self.state.get_withdrawal_status(outpoint)
+    }
+
+    /// Returns a merkle inclusion proof for a transaction, which light clients check with
+    /// `block::verify_transaction_proof`.
+    pub fn get_transaction_proof(
+        &self,
+        transaction_number: u64,
+    ) -> Result<Option<TransactionProof>> {
+        self.state.get_transaction_proof(transaction_number)
+    }
+
+    pub fn get_transaction_proof_by_hash(
+        &self,
+        transaction_hash: &[u8; HASH_LENGTH],
+    ) -> Result<Option<TransactionProof>> {
+        self.state.get_transaction_proof_by_hash(transaction_hash)
+    }
+
+    /// Returns the commitment to the utxo set right after the side block at `block_height` was
+    /// connected, which operators can compare across nodes instead of the whole utxo set.
+    pub fn get_utxo_set_commitment(&self, block_height: u32) -> Result<Option<[u8; HASH_LENGTH]>> {
+        self.state.get_utxo_set_commitment(block_height)
+    }
+
+    pub fn submit_transaction(&self, transaction: &AuthorizedTransaction) -> Result<()> {
         self.state.submit_transaction(transaction)?;
         Ok(())
     }
 
+    pub fn cancel_withdrawal(&self, transaction: &AuthorizedTransaction) -> Result<()> {
+        self.state.cancel_withdrawal(transaction)?;
+        Ok(())
+    }
+
     pub fn submit_block(
         &self,
         header: Header,
         coinbase: &[Output],
-        transactions: &[Transaction],
+        transactions: &[AuthorizedTransaction],
     ) -> Result<()> {
         self.state.connect(header, coinbase, transactions)?;
         Ok(())
@@ -111,6 +151,11 @@ impl Node {
         Ok(())
     }
 
+    pub fn disconnect_main_block(&self, block_hash: &[u8; HASH_LENGTH]) -> Result<()> {
+        self.state.disconnect_main_block(block_hash)?;
+        Ok(())
+    }
+
     fn run(&self) -> Result<()> {
         todo!();
     }
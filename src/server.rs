@@ -1,4 +1,8 @@
+use crate::authorization::AuthorizedTransaction;
+use crate::block::Header;
 use crate::node::Node;
+use crate::state::error::{BlockError, TransactionError};
+use crate::state::WithdrawalState;
 use bitcoin::consensus::Encodable;
 use cusf_sidechain_proto::sidechain::{
     sidechain_server::Sidechain, CollectTransactionsRequest, CollectTransactionsResponse,
@@ -8,12 +12,41 @@ use cusf_sidechain_proto::sidechain::{
     SubmitBlockRequest, SubmitBlockResponse, SubmitTransactionRequest, SubmitTransactionResponse,
 };
 use cusf_sidechain_types::{
-    Hashable, Header, MainBlock, OutPoint, Output, Transaction, WithdrawalBundleEvent,
-    WithdrawalBundleEventType, HASH_LENGTH,
+    MainBlock, OutPoint, Output, WithdrawalBundleEvent, WithdrawalBundleEventType, HASH_LENGTH,
 };
 use miette::IntoDiagnostic;
+use sidechain_extensions::{
+    get_transaction_proof_request, sidechain_extensions_server::SidechainExtensions,
+    CancelWithdrawalRequest, CancelWithdrawalResponse, GetTransactionProofRequest,
+    GetTransactionProofResponse, GetUtxoSetCommitmentRequest, GetUtxoSetCommitmentResponse,
+    GetWithdrawalStatusRequest, GetWithdrawalStatusResponse, PreviewWithdrawalBundleRequest,
+    PreviewWithdrawalBundleResponse,
+};
 use tonic::{Request, Response, Status};
 
+/// Service for methods that the `Sidechain` service from `cusf_sidechain_proto` doesn't have,
+/// defined in `proto/sidechain_extensions.proto`.
+pub mod sidechain_extensions {
+    tonic::include_proto!("sidechain_extensions");
+}
+
+/// Consensus errors are the caller's fault, and are returned with the full chain of reasons, so
+/// that block producers and wallets know exactly why something was rejected.
+fn into_status(err: miette::Report) -> Status {
+    let message = err
+        .chain()
+        .map(|err| err.to_string())
+        .collect::<Vec<_>>()
+        .join(": ");
+    if err.downcast_ref::<BlockError>().is_some()
+        || err.downcast_ref::<TransactionError>().is_some()
+    {
+        Status::invalid_argument(message)
+    } else {
+        Status::internal(message)
+    }
+}
+
 #[derive(Clone)]
 pub struct Plain {
     node: Node,
@@ -32,9 +65,11 @@ impl Sidechain for Plain {
         request: Request<SubmitTransactionRequest>,
     ) -> Result<Response<SubmitTransactionResponse>, Status> {
         let transaction_bytes = request.into_inner().transaction;
-        let transaction: Transaction = bincode::deserialize(&transaction_bytes).unwrap();
-        dbg!(&transaction);
-        self.node.submit_transaction(&transaction).unwrap();
+        let transaction: AuthorizedTransaction = bincode::deserialize(&transaction_bytes)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        self.node
+            .submit_transaction(&transaction)
+            .map_err(into_status)?;
         let response = SubmitTransactionResponse {};
         Ok(Response::new(response))
     }
@@ -44,13 +79,14 @@ impl Sidechain for Plain {
         request: Request<SubmitBlockRequest>,
     ) -> Result<Response<SubmitBlockResponse>, Status> {
         let block_bytes = request.into_inner().block;
-        let (header, coinbase, transactions): (Header, Vec<Output>, Vec<Transaction>) =
-            bincode::deserialize(&block_bytes).unwrap();
+        let (header, coinbase, transactions): (Header, Vec<Output>, Vec<AuthorizedTransaction>) =
+            bincode::deserialize(&block_bytes)
+                .map_err(|err| Status::invalid_argument(err.to_string()))?;
         let block_hash = hex::encode(&header.hash());
         println!("block {} submitted", block_hash);
         self.node
             .submit_block(header, &coinbase, &transactions)
-            .unwrap();
+            .map_err(into_status)?;
         let response = SubmitBlockResponse {};
         Ok(Response::new(response))
     }
@@ -83,41 +119,56 @@ impl Sidechain for Plain {
     ) -> Result<Response<ConnectMainBlockResponse>, Status> {
         let main_block = request.into_inner();
         let block_height = main_block.block_height;
-        let block_hash: [u8; HASH_LENGTH] = main_block.block_hash.try_into().unwrap();
-        let deposits = main_block.deposits;
-        let deposits = deposits
-            .into_iter()
-            .map(|deposit| {
-                (
-                    OutPoint::Deposit {
-                        sequence_number: deposit.sequence_number,
-                    },
-                    Output::Regular {
-                        address: deposit.address.try_into().unwrap(),
-                        value: deposit.value,
-                    },
-                )
-            })
-            .collect();
-        let withdrawal_bundle_event =
-            main_block
-                .withdrawal_bundle_event
-                .map(|withdrawal_bundle_event| WithdrawalBundleEvent {
-                    withdrawal_bundle_event_type: match withdrawal_bundle_event
-                        .withdrawal_bundle_event_type
-                    {
+        let block_hash: [u8; HASH_LENGTH] = main_block
+            .block_hash
+            .try_into()
+            .map_err(|_| Status::invalid_argument("block hash must be 32 bytes"))?;
+        let mut deposits = vec![];
+        for deposit in main_block.deposits {
+            let address = deposit
+                .address
+                .try_into()
+                .map_err(|_| Status::invalid_argument("deposit address must be 20 bytes"))?;
+            let outpoint = OutPoint::Deposit {
+                sequence_number: deposit.sequence_number,
+            };
+            let output = Output::Regular {
+                address,
+                value: deposit.value,
+            };
+            deposits.push((outpoint, output));
+        }
+        let withdrawal_bundle_event = match main_block.withdrawal_bundle_event {
+            Some(withdrawal_bundle_event) => {
+                let withdrawal_bundle_event_type =
+                    match withdrawal_bundle_event.withdrawal_bundle_event_type {
                         0 => WithdrawalBundleEventType::Submitted,
                         1 => WithdrawalBundleEventType::Failed,
                         2 => WithdrawalBundleEventType::Succeded,
-                        _ => todo!(),
-                    },
-                    m6id: withdrawal_bundle_event.m6id.try_into().unwrap(),
-                });
-        let bmm_hashes = main_block
-            .bmm_hashes
-            .into_iter()
-            .map(|bmm_hash| bmm_hash.try_into().unwrap())
-            .collect();
+                        event_type => {
+                            return Err(Status::invalid_argument(format!(
+                                "unknown withdrawal bundle event type {event_type}"
+                            )))
+                        }
+                    };
+                let m6id = withdrawal_bundle_event
+                    .m6id
+                    .try_into()
+                    .map_err(|_| Status::invalid_argument("m6id must be 32 bytes"))?;
+                Some(WithdrawalBundleEvent {
+                    withdrawal_bundle_event_type,
+                    m6id,
+                })
+            }
+            None => None,
+        };
+        let mut bmm_hashes = vec![];
+        for bmm_hash in main_block.bmm_hashes {
+            let bmm_hash = bmm_hash
+                .try_into()
+                .map_err(|_| Status::invalid_argument("bmm hash must be 32 bytes"))?;
+            bmm_hashes.push(bmm_hash);
+        }
         let block = MainBlock {
             block_height,
             block_hash,
@@ -125,7 +176,7 @@ impl Sidechain for Plain {
             withdrawal_bundle_event,
             bmm_hashes,
         };
-        self.node.connect_main_block(&block).unwrap();
+        self.node.connect_main_block(&block).map_err(into_status)?;
         let response = ConnectMainBlockResponse {};
         Ok(Response::new(response))
     }
@@ -134,7 +185,17 @@ impl Sidechain for Plain {
         &self,
         request: Request<DisconnectMainBlockRequest>,
     ) -> Result<Response<DisconnectMainBlockResponse>, Status> {
-        todo!();
+        let block_hash: [u8; HASH_LENGTH] = request
+            .into_inner()
+            .block_hash
+            .try_into()
+            .map_err(|_| Status::invalid_argument("block hash must be 32 bytes"))?;
+        println!("main block {} disconnected", hex::encode(block_hash));
+        self.node
+            .disconnect_main_block(&block_hash)
+            .map_err(into_status)?;
+        let response = DisconnectMainBlockResponse {};
+        Ok(Response::new(response))
     }
 
     async fn get_utxo_set(
@@ -160,3 +221,122 @@ impl Sidechain for Plain {
         Ok(Response::new(response))
     }
 }
+
+#[tonic::async_trait]
+impl SidechainExtensions for Plain {
+    async fn cancel_withdrawal(
+        &self,
+        request: Request<CancelWithdrawalRequest>,
+    ) -> Result<Response<CancelWithdrawalResponse>, Status> {
+        let transaction_bytes = request.into_inner().transaction;
+        let transaction: AuthorizedTransaction = bincode::deserialize(&transaction_bytes)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        self.node
+            .cancel_withdrawal(&transaction)
+            .map_err(into_status)?;
+        let response = CancelWithdrawalResponse {};
+        Ok(Response::new(response))
+    }
+
+    async fn preview_withdrawal_bundle(
+        &self,
+        _request: Request<PreviewWithdrawalBundleRequest>,
+    ) -> Result<Response<PreviewWithdrawalBundleResponse>, Status> {
+        let preview = self.node.preview_withdrawal_bundle().map_err(into_status)?;
+        let mut bundle = vec![];
+        preview
+            .bundle
+            .consensus_encode(&mut bundle)
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let outpoints = bincode::serialize(&preview.outpoints)
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let response = PreviewWithdrawalBundleResponse {
+            m6id: preview.m6id.to_vec(),
+            bundle,
+            outpoints,
+            total_fee: preview.total_fee,
+            weight: preview.weight,
+            pending: preview.pending,
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn get_withdrawal_status(
+        &self,
+        request: Request<GetWithdrawalStatusRequest>,
+    ) -> Result<Response<GetWithdrawalStatusResponse>, Status> {
+        let outpoint_bytes = request.into_inner().outpoint;
+        let outpoint: OutPoint = bincode::deserialize(&outpoint_bytes)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let transitions = self
+            .node
+            .get_withdrawal_status(&outpoint)
+            .map_err(into_status)?
+            .ok_or_else(|| Status::not_found(format!("{outpoint} was never a withdrawal")))?;
+        let transitions = transitions
+            .into_iter()
+            .map(|transition| {
+                let state = match transition.state {
+                    WithdrawalState::Unlocked => sidechain_extensions::WithdrawalState::Unlocked,
+                    WithdrawalState::Locked => sidechain_extensions::WithdrawalState::Locked,
+                    WithdrawalState::Submitted => sidechain_extensions::WithdrawalState::Submitted,
+                    WithdrawalState::PaidOut => sidechain_extensions::WithdrawalState::PaidOut,
+                    WithdrawalState::Returned => sidechain_extensions::WithdrawalState::Returned,
+                    WithdrawalState::Cancelled => sidechain_extensions::WithdrawalState::Cancelled,
+                };
+                sidechain_extensions::WithdrawalTransition {
+                    state: state.into(),
+                    m6id: transition
+                        .m6id
+                        .map(|m6id| m6id.to_vec())
+                        .unwrap_or_default(),
+                    main_block_height: transition.main_block_height,
+                }
+            })
+            .collect();
+        let response = GetWithdrawalStatusResponse { transitions };
+        Ok(Response::new(response))
+    }
+
+    async fn get_transaction_proof(
+        &self,
+        request: Request<GetTransactionProofRequest>,
+    ) -> Result<Response<GetTransactionProofResponse>, Status> {
+        let proof = match request.into_inner().transaction {
+            Some(get_transaction_proof_request::Transaction::TransactionNumber(
+                transaction_number,
+            )) => self.node.get_transaction_proof(transaction_number),
+            Some(get_transaction_proof_request::Transaction::TransactionHash(transaction_hash)) => {
+                let transaction_hash: [u8; HASH_LENGTH] = transaction_hash
+                    .try_into()
+                    .map_err(|_| Status::invalid_argument("transaction hash must be 32 bytes"))?;
+                self.node.get_transaction_proof_by_hash(&transaction_hash)
+            }
+            None => return Err(Status::invalid_argument("no transaction given")),
+        };
+        let proof = proof
+            .map_err(into_status)?
+            .ok_or_else(|| Status::not_found("transaction isn't in an archived block"))?;
+        let proof = bincode::serialize(&proof).map_err(|err| Status::internal(err.to_string()))?;
+        let response = GetTransactionProofResponse { proof };
+        Ok(Response::new(response))
+    }
+
+    async fn get_utxo_set_commitment(
+        &self,
+        request: Request<GetUtxoSetCommitmentRequest>,
+    ) -> Result<Response<GetUtxoSetCommitmentResponse>, Status> {
+        let block_height = request.into_inner().block_height;
+        let commitment = self
+            .node
+            .get_utxo_set_commitment(block_height)
+            .map_err(into_status)?
+            .ok_or_else(|| {
+                Status::not_found(format!("no utxo set commitment for block {block_height}"))
+            })?;
+        let response = GetUtxoSetCommitmentResponse {
+            commitment: commitment.to_vec(),
+        };
+        Ok(Response::new(response))
+    }
+}
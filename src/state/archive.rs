@@ -2,26 +2,36 @@ use heed::{types::*, Env, RoTxn};
 use heed::{Database, RwTxn};
 use miette::{miette, IntoDiagnostic, Result};
 
-use cusf_sidechain_types::{Hashable, Header, Output, Transaction, HASH_LENGTH};
+use cusf_sidechain_types::{Output, Transaction, HASH_LENGTH};
+
+use super::codec::{self, BigEndian};
+use super::error::BlockError;
+use crate::authorization::AuthorizedTransaction;
+use crate::block::{self, Header, TransactionProof};
 
 #[derive(Clone)]
 pub struct Archive {
     /// Block number -> (Header, (Transactions range))
-    pub headers: Database<SerdeBincode<u32>, SerdeBincode<(Header, (u64, u64))>>,
+    pub headers: Database<BigEndian<u32>, SerdeBincode<(Header, (u64, u64))>>,
     /// Block number -> Coinbase
-    pub coinbases: Database<SerdeBincode<u32>, SerdeBincode<Vec<Output>>>,
+    pub coinbases: Database<BigEndian<u32>, SerdeBincode<Vec<Output>>>,
     /// Transaction sequence number -> Transaction
-    pub transactions: Database<SerdeBincode<u64>, SerdeBincode<Transaction>>,
+    pub transactions: Database<BigEndian<u64>, SerdeBincode<AuthorizedTransaction>>,
+    /// Transaction hash -> Transaction sequence number
+    pub transaction_numbers: Database<SerdeBincode<[u8; HASH_LENGTH]>, SerdeBincode<u64>>,
     pub bmm_hashes: Database<SerdeBincode<[u8; HASH_LENGTH]>, Unit>,
 }
 
 impl Archive {
-    pub const NUM_DBS: u32 = 4;
+    pub const NUM_DBS: u32 = 5;
 
     pub fn new(env: &Env) -> Result<Self> {
         let transactions = env
             .create_database(Some("archive_transactions"))
             .into_diagnostic()?;
+        let transaction_numbers = env
+            .create_database(Some("archive_transaction_numbers"))
+            .into_diagnostic()?;
         let coinbases = env.create_database(Some("coinbase")).into_diagnostic()?;
         let headers = env
             .create_database(Some("archive_headers"))
@@ -31,47 +41,234 @@ impl Archive {
             headers,
             coinbases,
             transactions,
+            transaction_numbers,
             bmm_hashes,
         })
     }
 
+    /// Rewrites an archive written before data versions existed.
+    ///
+    /// Keys were encoded little endian, transactions were stored without authorizations, and
+    /// headers were `cusf_sidechain_types::Header`, which doesn't commit to the block body. Every
+    /// header gets the merkle root of its block, which changes its hash, so `prev_side_block_hash`
+    /// is relinked to the rewritten previous header. Archived blocks were BMM'd under their old
+    /// hashes, and new blocks build on the rewritten chain tip.
+    ///
+    /// The transaction number index didn't exist either, and is built from the transactions.
+    pub fn migrate_from_v0(&self, txn: &mut RwTxn) -> Result<()> {
+        codec::migrate_keys_from_bincode(txn, &self.headers)?;
+        codec::migrate_keys_from_bincode(txn, &self.coinbases)?;
+        codec::migrate_keys_from_bincode(txn, &self.transactions)?;
+        codec::migrate_values(txn, &self.transactions, |transaction: Transaction| {
+            AuthorizedTransaction {
+                transaction,
+                withdrawal_destinations: vec![],
+                unconfirmed_inputs: vec![],
+                authorizations: vec![],
+            }
+        })?;
+        let v0_headers = self
+            .headers
+            .remap_data_type::<SerdeBincode<(cusf_sidechain_types::Header, (u64, u64))>>();
+        let mut blocks = vec![];
+        for item in v0_headers.iter(txn).into_diagnostic()? {
+            let (block_number, (_header, transaction_range)) = item.into_diagnostic()?;
+            blocks.push((block_number, transaction_range));
+        }
+        let mut prev_side_block_hash = [0; HASH_LENGTH];
+        for (block_number, (transaction_range_start, transaction_range_end)) in blocks {
+            let coinbase = self
+                .get_coinbase(txn, block_number)?
+                .ok_or(miette!("coinbase of block {block_number} doesn't exist"))?;
+            let mut transactions = vec![];
+            for transaction_number in transaction_range_start..transaction_range_end {
+                let transaction = self
+                    .transactions
+                    .get(txn, &transaction_number)
+                    .into_diagnostic()?
+                    .ok_or(miette!("transaction {transaction_number} doesn't exist"))?;
+                self.transaction_numbers
+                    .put(txn, &transaction.hash(), &transaction_number)
+                    .into_diagnostic()?;
+                transactions.push(transaction);
+            }
+            let header = Header {
+                prev_side_block_hash,
+                merkle_root: block::compute_merkle_root(&coinbase, &transactions),
+            };
+            prev_side_block_hash = header.hash();
+            self.headers
+                .put(
+                    txn,
+                    &block_number,
+                    &(header, (transaction_range_start, transaction_range_end)),
+                )
+                .into_diagnostic()?;
+        }
+        Ok(())
+    }
+
     pub fn validate_header(&self, txn: &RoTxn, header: &Header) -> Result<()> {
         let block_hash = header.hash();
         self.bmm_hashes
             .get(txn, &block_hash)
             .into_diagnostic()?
-            .ok_or(miette!("block header wasn't blind merge mined"))?;
+            .ok_or(BlockError::NotBmmd)?;
         let prev_block_hash = match self.get_chain_tip(txn)? {
             Some((_block_number, (header, (_, _)))) => header.hash(),
             None => [0; HASH_LENGTH],
         };
         if header.prev_side_block_hash != prev_block_hash {
-            return Err(miette!("wrong prev_side_block_hash"));
+            return Err(BlockError::WrongPrevSideBlockHash {
+                expected: prev_block_hash,
+                actual: header.prev_side_block_hash,
+            }
+            .into());
         }
         Ok(())
     }
 
-    pub fn add_bmm_hashes(&self, txn: &mut RwTxn, bmm_hashes: &[[u8; HASH_LENGTH]]) -> Result<()> {
+    /// Returns the BMM hashes that weren't already known.
+    pub fn add_bmm_hashes(
+        &self,
+        txn: &mut RwTxn,
+        bmm_hashes: &[[u8; HASH_LENGTH]],
+    ) -> Result<Vec<[u8; HASH_LENGTH]>> {
+        let mut added = vec![];
         for bmm_hash in bmm_hashes {
+            if self
+                .bmm_hashes
+                .get(txn, bmm_hash)
+                .into_diagnostic()?
+                .is_some()
+            {
+                continue;
+            }
             self.bmm_hashes.put(txn, bmm_hash, &()).into_diagnostic()?;
+            added.push(*bmm_hash);
+        }
+        Ok(added)
+    }
+
+    pub fn remove_bmm_hashes(
+        &self,
+        txn: &mut RwTxn,
+        bmm_hashes: &[[u8; HASH_LENGTH]],
+    ) -> Result<()> {
+        for bmm_hash in bmm_hashes {
+            self.bmm_hashes.delete(txn, bmm_hash).into_diagnostic()?;
         }
         Ok(())
     }
 
+    pub fn is_bmm_hash(&self, txn: &RoTxn, bmm_hash: &[u8; HASH_LENGTH]) -> Result<bool> {
+        Ok(self
+            .bmm_hashes
+            .get(txn, bmm_hash)
+            .into_diagnostic()?
+            .is_some())
+    }
+
     pub fn get_chain_tip(&self, txn: &RoTxn) -> Result<Option<(u32, (Header, (u64, u64)))>> {
         Ok(self.headers.last(txn).into_diagnostic()?)
     }
 
+    pub fn get_block(
+        &self,
+        txn: &RoTxn,
+        block_number: u32,
+    ) -> Result<Option<(Header, Vec<AuthorizedTransaction>)>> {
+        let Some((header, (transaction_range_start, transaction_range_end))) =
+            self.headers.get(txn, &block_number).into_diagnostic()?
+        else {
+            return Ok(None);
+        };
+        let mut transactions = vec![];
+        for transaction_number in transaction_range_start..transaction_range_end {
+            let transaction = self
+                .transactions
+                .get(txn, &transaction_number)
+                .into_diagnostic()?
+                .ok_or(miette!("transaction {transaction_number} doesn't exist"))?;
+            transactions.push(transaction);
+        }
+        Ok(Some((header, transactions)))
+    }
+
     pub fn get_coinbase(&self, txn: &RoTxn, block_number: u32) -> Result<Option<Vec<Output>>> {
         Ok(self.coinbases.get(txn, &block_number).into_diagnostic()?)
     }
 
+    pub fn get_transaction_number(
+        &self,
+        txn: &RoTxn,
+        transaction_hash: &[u8; HASH_LENGTH],
+    ) -> Result<Option<u64>> {
+        Ok(self
+            .transaction_numbers
+            .get(txn, transaction_hash)
+            .into_diagnostic()?)
+    }
+
+    pub fn get_transaction_hash(
+        &self,
+        txn: &RoTxn,
+        transaction_number: u64,
+    ) -> Result<Option<[u8; HASH_LENGTH]>> {
+        let transaction = self
+            .transactions
+            .get(txn, &transaction_number)
+            .into_diagnostic()?;
+        Ok(transaction.map(|transaction| transaction.hash()))
+    }
+
+    /// Returns the header of the block that includes the transaction, and a merkle proof of its
+    /// inclusion.
+    pub fn get_transaction_proof(
+        &self,
+        txn: &RoTxn,
+        transaction_number: u64,
+    ) -> Result<Option<TransactionProof>> {
+        // Recent transactions are the most likely to be asked for, so blocks are searched from
+        // the tip.
+        let mut containing_block = None;
+        for item in self.headers.rev_iter(txn).into_diagnostic()? {
+            let (number, (_header, (transaction_range_start, transaction_range_end))) =
+                item.into_diagnostic()?;
+            if transaction_number >= transaction_range_end {
+                break;
+            }
+            if transaction_number >= transaction_range_start {
+                containing_block = Some((number, transaction_range_start));
+                break;
+            }
+        }
+        let Some((block_number, transaction_range_start)) = containing_block else {
+            return Ok(None);
+        };
+        let (header, transactions) = self
+            .get_block(txn, block_number)?
+            .ok_or(miette!("block {block_number} doesn't exist"))?;
+        let coinbase = self
+            .get_coinbase(txn, block_number)?
+            .ok_or(miette!("coinbase of block {block_number} doesn't exist"))?;
+        let index = (transaction_number - transaction_range_start) as usize;
+        let proof = block::get_merkle_proof(&coinbase, &transactions, index).to_bytes();
+        Ok(Some(TransactionProof {
+            block_number,
+            header,
+            index,
+            leaves_count: transactions.len() + 1,
+            proof,
+        }))
+    }
+
     pub fn connect(
         &self,
         txn: &mut RwTxn,
         header: Header,
         coinbase: &[Output],
-        transactions: &[Transaction],
+        transactions: &[AuthorizedTransaction],
     ) -> Result<()> {
         let last_transaction = self.transactions.last(txn).into_diagnostic()?;
         let mut transaction_number = match last_transaction {
@@ -83,6 +280,9 @@ impl Archive {
             self.transactions
                 .put(txn, &transaction_number, transaction)
                 .into_diagnostic()?;
+            self.transaction_numbers
+                .put(txn, &transaction.hash(), &transaction_number)
+                .into_diagnostic()?;
             transaction_number += 1;
         }
         let transaction_range_end = transaction_number;
@@ -105,8 +305,152 @@ impl Archive {
         Ok(())
     }
 
-    // Disconnect number latest blocks.
-    pub fn disconnect(&self, txn: &mut RwTxn, number: u32) -> Result<()> {
-        todo!();
+    /// Disconnect number latest blocks.
+    ///
+    /// Returns the transactions of the disconnected blocks in the order they were connected.
+    pub fn disconnect(&self, txn: &mut RwTxn, number: u32) -> Result<Vec<AuthorizedTransaction>> {
+        let mut blocks = vec![];
+        for _ in 0..number {
+            let (block_number, (_header, (transaction_range_start, transaction_range_end))) = self
+                .headers
+                .last(txn)
+                .into_diagnostic()?
+                .ok_or(miette!("no blocks to disconnect"))?;
+            let mut transactions = vec![];
+            for transaction_number in transaction_range_start..transaction_range_end {
+                let transaction = self
+                    .transactions
+                    .get(txn, &transaction_number)
+                    .into_diagnostic()?
+                    .ok_or(miette!("transaction {transaction_number} doesn't exist"))?;
+                self.transactions
+                    .delete(txn, &transaction_number)
+                    .into_diagnostic()?;
+                self.transaction_numbers
+                    .delete(txn, &transaction.hash())
+                    .into_diagnostic()?;
+                transactions.push(transaction);
+            }
+            self.coinbases
+                .delete(txn, &block_number)
+                .into_diagnostic()?;
+            self.headers.delete(txn, &block_number).into_diagnostic()?;
+            blocks.push(transactions);
+        }
+        let transactions = blocks.into_iter().rev().flatten().collect();
+        Ok(transactions)
+    }
+
+    /// Raw contents of every database, for exact comparisons in tests.
+    #[cfg(test)]
+    pub fn dump(&self, txn: &RoTxn) -> Vec<crate::test_utils::Dump> {
+        use crate::test_utils::dump_db;
+        vec![
+            dump_db(txn, &self.headers),
+            dump_db(txn, &self.coinbases),
+            dump_db(txn, &self.transactions),
+            dump_db(txn, &self.transaction_numbers),
+            dump_db(txn, &self.bmm_hashes),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_env, unsigned};
+    use cusf_sidechain_types::{OutPoint, Transaction};
+
+    fn transaction(sequence_number: u64) -> AuthorizedTransaction {
+        unsigned(Transaction {
+            inputs: vec![OutPoint::Deposit { sequence_number }],
+            outputs: vec![],
+        })
+    }
+
+    #[test]
+    fn archived_transaction_proofs_verify() {
+        let (_dir, env) = new_env(Archive::NUM_DBS);
+        let archive = Archive::new(&env).unwrap();
+        let mut txn = env.write_txn().unwrap();
+        // The second block has an odd number of leaves, the coinbase included.
+        let mut prev_side_block_hash = [0; HASH_LENGTH];
+        for block in [vec![transaction(0)], (1..6).map(transaction).collect()] {
+            let header = Header {
+                prev_side_block_hash,
+                merkle_root: block::compute_merkle_root(&[], &block),
+            };
+            prev_side_block_hash = header.hash();
+            archive.connect(&mut txn, header, &[], &block).unwrap();
+        }
+        for transaction_number in 0..6 {
+            let transaction_hash = transaction(transaction_number).hash();
+            let proof = archive
+                .get_transaction_proof(&txn, transaction_number)
+                .unwrap()
+                .unwrap();
+            assert!(block::verify_transaction_proof(&proof, &transaction_hash));
+            let other_hash = transaction(transaction_number + 1).hash();
+            assert!(!block::verify_transaction_proof(&proof, &other_hash));
+        }
+        assert!(archive.get_transaction_proof(&txn, 6).unwrap().is_none());
+    }
+
+    #[test]
+    fn migrate_from_v0_commits_headers_to_their_blocks_and_indexes_transactions() {
+        let (_dir, env) = new_env(Archive::NUM_DBS);
+        let archive = Archive::new(&env).unwrap();
+        let mut txn = env.write_txn().unwrap();
+        let v0_headers = archive.headers.remap_types::<
+            SerdeBincode<u32>,
+            SerdeBincode<(cusf_sidechain_types::Header, (u64, u64))>,
+        >();
+        let v0_coinbases = archive.coinbases.remap_key_type::<SerdeBincode<u32>>();
+        let v0_transactions = archive
+            .transactions
+            .remap_types::<SerdeBincode<u64>, SerdeBincode<Transaction>>();
+        let coinbase = vec![Output::Regular {
+            address: [1; 20],
+            value: 1_000,
+        }];
+        // More transactions than fit in a byte, so little endian keys would sort out of order.
+        let blocks = [(1, 0..1), (2, 1..300)];
+        for (block_number, transaction_range) in blocks.clone() {
+            let header = cusf_sidechain_types::Header {
+                prev_side_block_hash: [block_number as u8; HASH_LENGTH],
+            };
+            let value = (header, (transaction_range.start, transaction_range.end));
+            v0_headers.put(&mut txn, &block_number, &value).unwrap();
+            v0_coinbases
+                .put(&mut txn, &block_number, &coinbase)
+                .unwrap();
+            for transaction_number in transaction_range {
+                let transaction = transaction(transaction_number).transaction;
+                v0_transactions
+                    .put(&mut txn, &transaction_number, &transaction)
+                    .unwrap();
+            }
+        }
+        archive.migrate_from_v0(&mut txn).unwrap();
+        let (first_header, first_block) = archive.get_block(&txn, 1).unwrap().unwrap();
+        assert_eq!(first_block.len(), 1);
+        let (block_number, (tip, _)) = archive.get_chain_tip(&txn).unwrap().unwrap();
+        assert_eq!(block_number, 2);
+        assert_eq!(first_header.prev_side_block_hash, [0; HASH_LENGTH]);
+        assert_eq!(tip.prev_side_block_hash, first_header.hash());
+        for transaction_number in 0..300 {
+            let proof = archive
+                .get_transaction_proof(&txn, transaction_number)
+                .unwrap()
+                .unwrap();
+            let transaction_hash = transaction(transaction_number).hash();
+            assert!(block::verify_transaction_proof(&proof, &transaction_hash));
+            assert_eq!(
+                archive
+                    .get_transaction_number(&txn, &transaction_hash)
+                    .unwrap(),
+                Some(transaction_number)
+            );
+        }
     }
 }
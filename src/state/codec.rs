@@ -0,0 +1,115 @@
+use heed::types::{ByteSlice, SerdeBincode};
+use heed::{BytesDecode, BytesEncode, Database, RwTxn};
+use miette::{IntoDiagnostic, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::borrow::Cow;
+use std::marker::PhantomData;
+
+/// Big endian integer key codec, so that LMDB's byte order matches numeric order.
+///
+/// SerdeBincode encodes integers little endian, so `first`, `last` and iteration order are wrong
+/// for numeric keys past 255.
+pub struct BigEndian<T>(PhantomData<T>);
+
+macro_rules! impl_big_endian {
+    ($int:ty) => {
+        impl<'a> BytesEncode<'a> for BigEndian<$int> {
+            type EItem = $int;
+
+            fn bytes_encode(item: &'a $int) -> Option<Cow<'a, [u8]>> {
+                Some(Cow::Owned(item.to_be_bytes().to_vec()))
+            }
+        }
+
+        impl<'a> BytesDecode<'a> for BigEndian<$int> {
+            type DItem = $int;
+
+            fn bytes_decode(bytes: &'a [u8]) -> Option<$int> {
+                Some(<$int>::from_be_bytes(bytes.try_into().ok()?))
+            }
+        }
+    };
+}
+
+impl_big_endian!(u32);
+impl_big_endian!(u64);
+
+/// Rewrites a database whose keys were written with SerdeBincode, so they use big endian keys.
+///
+/// Values are copied as they are, since they may still be in a format that a later migration
+/// rewrites.
+pub fn migrate_keys_from_bincode<K, DC>(
+    txn: &mut RwTxn,
+    db: &Database<BigEndian<K>, DC>,
+) -> Result<()>
+where
+    K: Serialize + DeserializeOwned + 'static,
+    DC: 'static,
+    BigEndian<K>: for<'a> BytesEncode<'a, EItem = K>,
+{
+    let bincode_db = db.remap_types::<SerdeBincode<K>, ByteSlice>();
+    let mut items = vec![];
+    for item in bincode_db.iter(txn).into_diagnostic()? {
+        let (key, value) = item.into_diagnostic()?;
+        items.push((key, value.to_vec()));
+    }
+    db.clear(txn).into_diagnostic()?;
+    let db = db.remap_data_type::<ByteSlice>();
+    for (key, value) in &items {
+        db.put(txn, key, value).into_diagnostic()?;
+    }
+    Ok(())
+}
+
+/// Rewrites every value of a database that was written with an older type.
+pub fn migrate_values<KC, Old, New>(
+    txn: &mut RwTxn,
+    db: &Database<KC, SerdeBincode<New>>,
+    convert: impl Fn(Old) -> New,
+) -> Result<()>
+where
+    KC: 'static,
+    Old: DeserializeOwned + 'static,
+    New: Serialize + 'static,
+{
+    let old_db = db.remap_types::<ByteSlice, SerdeBincode<Old>>();
+    let mut items = vec![];
+    for item in old_db.iter(txn).into_diagnostic()? {
+        let (key, value) = item.into_diagnostic()?;
+        items.push((key.to_vec(), convert(value)));
+    }
+    let db = db.remap_key_type::<ByteSlice>();
+    for (key, value) in &items {
+        db.put(txn, key, value).into_diagnostic()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::new_env;
+
+    #[test]
+    fn migrate_keys_from_bincode_keeps_every_entry_in_numeric_order() {
+        let (_dir, env) = new_env(1);
+        let db: Database<BigEndian<u64>, SerdeBincode<String>> =
+            env.create_database(Some("db")).unwrap();
+        let mut txn = env.write_txn().unwrap();
+        // Little endian keys sort 256 before 1.
+        let keys = [256, 1, u64::MAX, 0, 255, 1 << 32];
+        let bincode_db = db.remap_key_type::<SerdeBincode<u64>>();
+        for key in keys {
+            bincode_db.put(&mut txn, &key, &key.to_string()).unwrap();
+        }
+        migrate_keys_from_bincode(&mut txn, &db).unwrap();
+        let entries: Vec<_> = db.iter(&txn).unwrap().map(|item| item.unwrap()).collect();
+        let mut expected: Vec<_> = keys.iter().map(|key| (*key, key.to_string())).collect();
+        expected.sort();
+        assert_eq!(entries, expected);
+        assert_eq!(
+            db.last(&txn).unwrap(),
+            Some((u64::MAX, u64::MAX.to_string()))
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use cusf_sidechain_types::{OutPoint, HASH_LENGTH};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::authorization::UnconfirmedOutPoint;
+
+/// Reasons for rejecting a transaction, either on mempool admission or as part of a block.
+#[derive(Debug, Error, Diagnostic)]
+pub enum TransactionError {
+    #[error("input {0} doesn't exist")]
+    MissingInput(OutPoint),
+    #[error("input {0} is spent more than once")]
+    DoubleSpend(OutPoint),
+    #[error("unconfirmed input {0} doesn't exist")]
+    MissingUnconfirmedInput(UnconfirmedOutPoint),
+    #[error("unconfirmed input {0} is spent more than once")]
+    UnconfirmedDoubleSpend(UnconfirmedOutPoint),
+    #[error("input {0} is a withdrawal locked in a pending bundle")]
+    LockedWithdrawal(OutPoint),
+    #[error("input {0} isn't an unlocked withdrawal, so it can't be cancelled")]
+    NotUnlockedWithdrawal(OutPoint),
+    #[error("unconfirmed input {0} isn't an unlocked withdrawal, so it can't be cancelled")]
+    UnconfirmedCancellationInput(UnconfirmedOutPoint),
+    #[error("output {0} of a withdrawal cancellation isn't a regular output")]
+    NonRegularCancellationOutput(usize),
+    #[error(
+        "transaction would replace {count} mempool transactions, at most {limit} can be replaced"
+    )]
+    TooManyReplacements { count: usize, limit: usize },
+    #[error("transaction would replace its own ancestor {}", hex::encode(.0))]
+    ReplacesAncestor([u8; HASH_LENGTH]),
+    #[error(
+        "transaction and its mempool ancestors are {count} transactions, the limit is {limit}"
+    )]
+    TooManyAncestors { count: usize, limit: usize },
+    #[error("transaction and its mempool ancestors are {size} bytes, the limit is {limit}")]
+    AncestorsTooLarge { size: usize, limit: usize },
+    #[error(
+        "ancestor {} and its mempool descendants would be {count} transactions, the limit is \
+         {limit}",
+        hex::encode(.transaction_hash)
+    )]
+    TooManyDescendants {
+        transaction_hash: [u8; HASH_LENGTH],
+        count: usize,
+        limit: usize,
+    },
+    #[error(
+        "ancestor {} and its mempool descendants would be {size} bytes, the limit is {limit}",
+        hex::encode(.transaction_hash)
+    )]
+    DescendantsTooLarge {
+        transaction_hash: [u8; HASH_LENGTH],
+        size: usize,
+        limit: usize,
+    },
+    #[error("fee {fee} isn't higher than the fee {replaced_fee} of the replaced transactions")]
+    ReplacementFeeTooLow { fee: u64, replaced_fee: u64 },
+    #[error(
+        "fee rate {fee}/{size} isn't higher than fee rate {replaced_fee}/{replaced_size} of the \
+         replaced transactions"
+    )]
+    ReplacementFeeRateTooLow {
+        fee: u64,
+        size: usize,
+        replaced_fee: u64,
+        replaced_size: usize,
+    },
+    #[error(
+        "fee {fee} doesn't cover the fee {replaced_fee} of the replaced transactions plus the \
+         relay fee {relay_fee} of the replacement"
+    )]
+    ReplacementRelayFeeTooLow {
+        fee: u64,
+        replaced_fee: u64,
+        relay_fee: u64,
+    },
+    #[error("value out {value_out} is greater than value in {value_in}")]
+    Overspend { value_in: u64, value_out: u64 },
+    #[error("value in or value out is larger than u64::MAX")]
+    ValueOverflow,
+    #[error("transaction has {len} outputs, the limit is {limit}")]
+    TooManyOutputs { len: usize, limit: usize },
+    #[error(
+        "transaction has {withdrawals} withdrawal outputs but {destinations} withdrawal \
+         destinations"
+    )]
+    WrongWithdrawalDestinationCount {
+        withdrawals: usize,
+        destinations: usize,
+    },
+    #[error("withdrawal destination {0} can't be paid to on the mainchain")]
+    InvalidWithdrawalDestination(usize),
+    #[error("transaction has {inputs} inputs but {authorizations} authorizations")]
+    WrongAuthorizationCount {
+        inputs: usize,
+        authorizations: usize,
+    },
+    #[error("input {0} isn't owned by the authorizing key")]
+    WrongKey(OutPoint),
+    #[error("invalid signature for input {0}")]
+    BadSignature(OutPoint),
+    #[error("unconfirmed input {0} isn't owned by the authorizing key")]
+    WrongUnconfirmedKey(UnconfirmedOutPoint),
+    #[error("invalid signature for unconfirmed input {0}")]
+    BadUnconfirmedSignature(UnconfirmedOutPoint),
+}
+
+/// Reasons for rejecting a block.
+#[derive(Debug, Error, Diagnostic)]
+pub enum BlockError {
+    #[error("block header wasn't blind merge mined")]
+    NotBmmd,
+    #[error(
+        "wrong prev_side_block_hash {}, expected {}",
+        hex::encode(.actual),
+        hex::encode(.expected)
+    )]
+    WrongPrevSideBlockHash {
+        expected: [u8; HASH_LENGTH],
+        actual: [u8; HASH_LENGTH],
+    },
+    #[error(
+        "wrong merkle_root {}, expected {}",
+        hex::encode(.actual),
+        hex::encode(.expected)
+    )]
+    WrongMerkleRoot {
+        expected: [u8; HASH_LENGTH],
+        actual: [u8; HASH_LENGTH],
+    },
+    #[error("block size {size} is larger than BLOCK_SIZE_LIMIT {limit}")]
+    Oversize { size: usize, limit: usize },
+    #[error("coinbase has {len} outputs, the limit is {limit}")]
+    TooManyCoinbaseOutputs { len: usize, limit: usize },
+    #[error("coinbase value {coinbase_value} is greater than total fees {total_fees}")]
+    CoinbaseOverpay {
+        coinbase_value: u64,
+        total_fees: u64,
+    },
+    #[error("coinbase value or total fees are larger than u64::MAX")]
+    ValueOverflow,
+    #[error("transaction {index} is invalid")]
+    InvalidTransaction {
+        index: usize,
+        #[source]
+        #[diagnostic_source]
+        source: TransactionError,
+    },
+}
@@ -1,18 +1,50 @@
-use cusf_sidechain_types::{Hashable, Transaction, BLOCK_SIZE_LIMIT, HASH_LENGTH};
+use super::error::TransactionError;
+use crate::authorization::{AuthorizedTransaction, UnconfirmedOutPoint};
+use cusf_sidechain_types::{OutPoint, Output, BLOCK_SIZE_LIMIT, HASH_LENGTH};
 use heed::{types::*, Env, RoTxn};
 use heed::{Database, RwTxn};
 use miette::{miette, IntoDiagnostic, Result};
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::time::SystemTime;
 
+/// Maximum number of mempool transactions a single transaction can replace, like in BIP125.
+const MAX_REPLACEMENTS: usize = 100;
+
+/// Fee per byte a replacement pays on top of the fees it replaces, like the incremental relay fee
+/// in BIP125.
+const MIN_RELAY_FEE_RATE: u64 = 1;
+
+/// Maximum number of transactions in a chain of mempool transactions, counting a transaction
+/// together with its mempool ancestors, or together with its mempool descendants, like the default
+/// ancestor and descendant limits of Bitcoin Core.
+const MAX_CHAIN_LEN: usize = 25;
+
+/// Maximum total size in bytes of a transaction together with its mempool ancestors, or together
+/// with its mempool descendants.
+const MAX_CHAIN_SIZE: usize = 101_000;
+
+/// Outputs a transaction spends, under every outpoint that refers to them.
+///
+/// Outputs of transactions that are in a block can be spent both by `OutPoint::Regular` and by
+/// [`UnconfirmedOutPoint`], so two transactions conflict if they spend the same output under
+/// either of them.
+#[derive(Debug, Default)]
+pub struct SpentOutputs {
+    pub outpoints: Vec<OutPoint>,
+    pub unconfirmed_outpoints: Vec<UnconfirmedOutPoint>,
+}
+
 #[derive(Clone)]
 pub struct Mempool {
     // Transaction hash -> (transaction, fee, unix timestamp)
     hash_to_transaction_fee_timestamp:
-        Database<SerdeBincode<[u8; HASH_LENGTH]>, SerdeBincode<(Transaction, u64, u64)>>,
-    // Fee -> (hash, size, unix timestampe time)
-    fee_to_hashes_sizes_timestamps:
-        Database<SerdeBincode<u64>, SerdeBincode<Vec<([u8; HASH_LENGTH], u32, u64)>>>,
+        Database<SerdeBincode<[u8; HASH_LENGTH]>, SerdeBincode<(AuthorizedTransaction, u64, u64)>>,
+    // Outpoint -> hash of the mempool transaction spending it
+    spent_outpoints: Database<SerdeBincode<OutPoint>, SerdeBincode<[u8; HASH_LENGTH]>>,
+    // Unconfirmed outpoint -> hash of the mempool transaction spending it
+    spent_unconfirmed_outpoints:
+        Database<SerdeBincode<UnconfirmedOutPoint>, SerdeBincode<[u8; HASH_LENGTH]>>,
 }
 
 impl Mempool {
@@ -22,16 +54,36 @@ impl Mempool {
         let hash_to_transaction_fee_timestamp = env
             .create_database(Some("mempool_hash_to_transaction_fee_timestamps"))
             .into_diagnostic()?;
-        let fee_to_hashes_sizes_timestamps = env
-            .create_database(Some("mempool_fee_to_hashes_sizes_timestamps"))
+        let spent_outpoints = env
+            .create_database(Some("mempool_spent_outpoints"))
+            .into_diagnostic()?;
+        let spent_unconfirmed_outpoints = env
+            .create_database(Some("mempool_spent_unconfirmed_outpoints"))
             .into_diagnostic()?;
         Ok(Self {
             hash_to_transaction_fee_timestamp,
-            fee_to_hashes_sizes_timestamps,
+            spent_outpoints,
+            spent_unconfirmed_outpoints,
         })
     }
 
-    pub fn connect(&self, txn: &mut RwTxn, transactions: &[Transaction]) -> Result<()> {
+    /// Drops the transactions of a mempool written before data versions existed.
+    ///
+    /// They were stored without authorizations, so they can't be included in a block anymore.
+    pub fn migrate_from_v0(&self, txn: &mut RwTxn) -> Result<()> {
+        self.hash_to_transaction_fee_timestamp
+            .clear(txn)
+            .into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Removes the transactions included in a block.
+    ///
+    /// Block transactions that never went through this mempool are ignored. Mempool transactions
+    /// that conflict with the block are left for [`crate::state::State`] to evict, since that
+    /// requires the utxo set. Mempool transactions spending outputs of the block transactions stay,
+    /// since their unconfirmed inputs now spend confirmed outputs.
+    pub fn connect(&self, txn: &mut RwTxn, transactions: &[AuthorizedTransaction]) -> Result<()> {
         for transaction in transactions {
             let transaction_hash = transaction.hash();
             self.remove(txn, &transaction_hash)?;
@@ -39,58 +91,278 @@ impl Mempool {
         Ok(())
     }
 
-    pub fn collect_transactions(&self, txn: &RoTxn) -> Result<Vec<Transaction>> {
-        let mut spent_utxos = HashSet::new();
+    /// Returns every mempool transaction with its hash and the fee it was admitted with.
+    pub fn get_transactions(
+        &self,
+        txn: &RoTxn,
+    ) -> Result<Vec<([u8; HASH_LENGTH], AuthorizedTransaction, u64)>> {
         let mut transactions = vec![];
         for item in self
-            .fee_to_hashes_sizes_timestamps
-            .rev_iter(txn)
+            .hash_to_transaction_fee_timestamp
+            .iter(txn)
             .into_diagnostic()?
         {
-            let (_fee, hashes_sizes_timestamps) = item.into_diagnostic()?;
-            let transactions_bytes = bincode::serialize(&transactions).into_diagnostic()?;
-            let block_size = transactions_bytes.len();
-            'outer: for (hash, size, _timestamp) in hashes_sizes_timestamps {
-                if block_size + size as usize > BLOCK_SIZE_LIMIT {
-                    break;
-                }
-                let (transaction, _fee, _timestamp) = self
-                    .hash_to_transaction_fee_timestamp
-                    .get(txn, &hash)
+            let (transaction_hash, (transaction, fee, _timestamp)) = item.into_diagnostic()?;
+            transactions.push((transaction_hash, transaction, fee));
+        }
+        Ok(transactions)
+    }
+
+    /// Returns true if the transaction is in the mempool.
+    pub fn contains(&self, txn: &RoTxn, transaction_hash: &[u8; HASH_LENGTH]) -> Result<bool> {
+        let contains = self
+            .hash_to_transaction_fee_timestamp
+            .get(txn, transaction_hash)
+            .into_diagnostic()?
+            .is_some();
+        Ok(contains)
+    }
+
+    /// Returns the output an unconfirmed outpoint refers to, if its transaction is in the mempool.
+    pub fn get_output(
+        &self,
+        txn: &RoTxn,
+        outpoint: &UnconfirmedOutPoint,
+    ) -> Result<Option<Output>> {
+        let Some((transaction, _fee, _timestamp)) = self
+            .hash_to_transaction_fee_timestamp
+            .get(txn, &outpoint.transaction_hash)
+            .into_diagnostic()?
+        else {
+            return Ok(None);
+        };
+        let output = transaction
+            .transaction
+            .outputs
+            .get(outpoint.output_number as usize)
+            .cloned();
+        Ok(output)
+    }
+
+    /// Returns the hashes of the mempool transactions whose outputs the transaction spends,
+    /// directly or through other mempool transactions.
+    fn get_ancestors(
+        &self,
+        txn: &RoTxn,
+        transaction: &AuthorizedTransaction,
+    ) -> Result<Vec<[u8; HASH_LENGTH]>> {
+        let mut ancestors = vec![];
+        let mut seen = HashSet::new();
+        let mut queue: Vec<_> = transaction
+            .unconfirmed_inputs
+            .iter()
+            .map(|input| input.transaction_hash)
+            .collect();
+        while let Some(transaction_hash) = queue.pop() {
+            if !seen.insert(transaction_hash) {
+                continue;
+            }
+            // Transactions that are in a block aren't in the mempool, and aren't ancestors.
+            let Some((ancestor, _fee, _timestamp)) = self
+                .hash_to_transaction_fee_timestamp
+                .get(txn, &transaction_hash)
+                .into_diagnostic()?
+            else {
+                continue;
+            };
+            queue.extend(
+                ancestor
+                    .unconfirmed_inputs
+                    .iter()
+                    .map(|input| input.transaction_hash),
+            );
+            ancestors.push(transaction_hash);
+        }
+        Ok(ancestors)
+    }
+
+    /// Returns the hashes of the mempool transactions, together with the hashes of the mempool
+    /// transactions spending their outputs, directly or through other mempool transactions.
+    fn get_with_descendants(
+        &self,
+        txn: &RoTxn,
+        transaction_hashes: &[[u8; HASH_LENGTH]],
+    ) -> Result<Vec<[u8; HASH_LENGTH]>> {
+        let mut transactions = vec![];
+        let mut seen = HashSet::new();
+        let mut queue = transaction_hashes.to_vec();
+        while let Some(transaction_hash) = queue.pop() {
+            if !seen.insert(transaction_hash) {
+                continue;
+            }
+            let Some((transaction, _fee, _timestamp)) = self
+                .hash_to_transaction_fee_timestamp
+                .get(txn, &transaction_hash)
+                .into_diagnostic()?
+            else {
+                continue;
+            };
+            for output_number in 0..transaction.transaction.outputs.len() {
+                let outpoint = UnconfirmedOutPoint {
+                    transaction_hash,
+                    output_number: output_number as u8,
+                };
+                if let Some(child) = self
+                    .spent_unconfirmed_outpoints
+                    .get(txn, &outpoint)
                     .into_diagnostic()?
-                    .ok_or(miette!("transaction doesn't exist"))?;
-                for input in &transaction.inputs {
-                    if spent_utxos.contains(input) {
-                        // If we see a transaction that spends the same utxo as an already included
-                        // transaction, we always keep the already included transaction, because it
-                        // is more desirable because it has the higher fee, smaller size, or is
-                        // older.
-                        //
-                        // The transactions in the mempool are sorted by fee, size, and timestamp,
-                        // and we are iterating in order, that is why the previously included
-                        // transaction is always more desirable.
-                        continue 'outer;
+                {
+                    queue.push(child);
+                }
+            }
+            transactions.push(transaction_hash);
+        }
+        Ok(transactions)
+    }
+
+    /// Selects transactions for a block template, by highest ancestor fee rate first, so that a
+    /// child paying a high fee gets the parents it spends from included too.
+    ///
+    /// Every transaction is selected as a package, together with its mempool ancestors that weren't
+    /// selected yet, and parents always come before their children. A package that doesn't fit
+    /// into the remaining space is skipped, and smaller packages after it are still tried, until
+    /// BLOCK_SIZE_LIMIT is reached.
+    pub fn collect_transactions(&self, txn: &RoTxn) -> Result<Vec<AuthorizedTransaction>> {
+        let mut entries = HashMap::new();
+        for item in self
+            .hash_to_transaction_fee_timestamp
+            .iter(txn)
+            .into_diagnostic()?
+        {
+            let (hash, (transaction, fee, timestamp)) = item.into_diagnostic()?;
+            let size = bincode::serialize(&transaction).into_diagnostic()?.len();
+            entries.insert(hash, (transaction, fee, size, timestamp));
+        }
+        let mut parents: HashMap<[u8; HASH_LENGTH], HashSet<[u8; HASH_LENGTH]>> = HashMap::new();
+        let mut children: HashMap<[u8; HASH_LENGTH], HashSet<[u8; HASH_LENGTH]>> = HashMap::new();
+        for (hash, (transaction, _fee, _size, _timestamp)) in &entries {
+            for input in &transaction.unconfirmed_inputs {
+                if entries.contains_key(&input.transaction_hash) {
+                    parents
+                        .entry(*hash)
+                        .or_default()
+                        .insert(input.transaction_hash);
+                    children
+                        .entry(input.transaction_hash)
+                        .or_default()
+                        .insert(*hash);
+                }
+            }
+        }
+        let mut ancestors = HashMap::new();
+        for hash in entries.keys() {
+            let mut transaction_ancestors = HashSet::new();
+            let mut queue = vec![*hash];
+            while let Some(hash) = queue.pop() {
+                for parent in parents.get(&hash).into_iter().flatten() {
+                    if transaction_ancestors.insert(*parent) {
+                        queue.push(*parent);
                     }
                 }
-                for input in &transaction.inputs {
-                    spent_utxos.insert(input.clone());
+            }
+            ancestors.insert(*hash, transaction_ancestors);
+        }
+        // Transaction together with its ancestors that weren't selected yet.
+        let get_package = |hash: &[u8; HASH_LENGTH], selected: &HashSet<[u8; HASH_LENGTH]>| {
+            let (_transaction, _fee, _size, timestamp) = &entries[hash];
+            let mut package = Package {
+                hash: *hash,
+                fee: 0,
+                size: 0,
+                timestamp: *timestamp,
+            };
+            for member in std::iter::once(hash).chain(&ancestors[hash]) {
+                if !selected.contains(member) {
+                    let (_transaction, fee, size, _timestamp) = &entries[member];
+                    package.fee += *fee;
+                    package.size += *size;
+                }
+            }
+            package
+        };
+        let mut selected = HashSet::new();
+        let mut candidates: BinaryHeap<_> = entries
+            .keys()
+            .map(|hash| get_package(hash, &selected))
+            .collect();
+        let mut spent_outpoints = HashSet::new();
+        let mut spent_unconfirmed_outpoints = HashSet::new();
+        let mut transactions: Vec<AuthorizedTransaction> = vec![];
+        // Transactions are serialized as a Vec, so the block size starts with the length prefix.
+        let mut block_size = bincode::serialize(&transactions).into_diagnostic()?.len();
+        while let Some(candidate) = candidates.pop() {
+            // Packages are pushed again whenever some of their ancestors get selected, which makes
+            // the packages that were pushed before outdated.
+            if selected.contains(&candidate.hash)
+                || get_package(&candidate.hash, &selected) != candidate
+            {
+                continue;
+            }
+            if block_size + candidate.size > BLOCK_SIZE_LIMIT {
+                continue;
+            }
+            let mut members: Vec<_> = std::iter::once(&candidate.hash)
+                .chain(&ancestors[&candidate.hash])
+                .filter(|member| !selected.contains(*member))
+                .copied()
+                .collect();
+            // Ancestors of a transaction have fewer ancestors than it does, so this puts parents
+            // before their children.
+            members.sort_by_key(|member| (ancestors[member].len(), *member));
+            // Mempool transactions don't conflict, unless a reorg made them conflict since they
+            // were admitted. In that case the already selected transaction is kept, since its
+            // package has a higher fee rate, is smaller, or is older.
+            let spends_selected = members.iter().any(|member| {
+                let (transaction, _fee, _size, _timestamp) = &entries[member];
+                transaction
+                    .transaction
+                    .inputs
+                    .iter()
+                    .any(|input| spent_outpoints.contains(input))
+                    || transaction
+                        .unconfirmed_inputs
+                        .iter()
+                        .any(|input| spent_unconfirmed_outpoints.contains(input))
+            });
+            if spends_selected {
+                continue;
+            }
+            for member in &members {
+                let (transaction, _fee, _size, _timestamp) = &entries[member];
+                spent_outpoints.extend(transaction.transaction.inputs.iter().cloned());
+                spent_unconfirmed_outpoints.extend(transaction.unconfirmed_inputs.iter().cloned());
+                selected.insert(*member);
+                transactions.push(transaction.clone());
+            }
+            block_size += candidate.size;
+            let mut queue = members;
+            let mut seen = HashSet::new();
+            while let Some(hash) = queue.pop() {
+                for child in children.get(&hash).into_iter().flatten() {
+                    if seen.insert(*child) && !selected.contains(child) {
+                        candidates.push(get_package(child, &selected));
+                        queue.push(*child);
+                    }
                 }
-                transactions.push(transaction);
             }
         }
         Ok(transactions)
     }
 
+    /// Adds a transaction that was checked against the utxo set, replacing the mempool transactions
+    /// it conflicts with if it pays enough.
+    ///
+    /// `spent` are the outputs the transaction spends, see [`SpentOutputs`]. Everything is checked
+    /// before anything is written, so a rejected transaction leaves the mempool as it was.
     pub fn submit_transaction(
         &self,
         txn: &mut RwTxn,
-        transaction: &Transaction,
+        transaction: &AuthorizedTransaction,
         fee: u64,
+        spent: &SpentOutputs,
     ) -> Result<()> {
         let transaction_bytes = bincode::serialize(&transaction).into_diagnostic()?;
         let transaction_size = transaction_bytes.len();
-        dbg!(&fee);
-        dbg!(&transaction_size);
         let transaction_hash = transaction.hash();
         if self
             .hash_to_transaction_fee_timestamp
@@ -101,6 +373,52 @@ impl Mempool {
             // If the transaction is already in the mempool, don't do anything.
             return Ok(());
         }
+        let mut conflicts = vec![];
+        for outpoint in &spent.outpoints {
+            if let Some(conflict) = self.spent_outpoints.get(txn, outpoint).into_diagnostic()? {
+                if !conflicts.contains(&conflict) {
+                    conflicts.push(conflict);
+                }
+            }
+        }
+        for outpoint in &spent.unconfirmed_outpoints {
+            if let Some(conflict) = self
+                .spent_unconfirmed_outpoints
+                .get(txn, outpoint)
+                .into_diagnostic()?
+            {
+                if !conflicts.contains(&conflict) {
+                    conflicts.push(conflict);
+                }
+            }
+        }
+        // Descendants of conflicting transactions can't be included without them, so they are
+        // replaced too.
+        let replaced = self.get_with_descendants(txn, &conflicts)?;
+        let ancestors = self.get_ancestors(txn, transaction)?;
+        if let Some(ancestor) = ancestors
+            .iter()
+            .find(|ancestor| replaced.contains(ancestor))
+        {
+            return Err(TransactionError::ReplacesAncestor(*ancestor).into());
+        }
+        if !replaced.is_empty() {
+            self.check_replacement(txn, &replaced, fee, transaction_size)?;
+        }
+        self.check_chain_limits(txn, &ancestors, &replaced, transaction_size)?;
+        for transaction_hash in &replaced {
+            self.remove(txn, transaction_hash)?;
+        }
+        for input in &transaction.transaction.inputs {
+            self.spent_outpoints
+                .put(txn, input, &transaction_hash)
+                .into_diagnostic()?;
+        }
+        for input in &transaction.unconfirmed_inputs {
+            self.spent_unconfirmed_outpoints
+                .put(txn, input, &transaction_hash)
+                .into_diagnostic()?;
+        }
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .into_diagnostic()?
@@ -112,43 +430,328 @@ impl Mempool {
                 &(transaction.clone(), fee, timestamp),
             )
             .into_diagnostic()?;
-        let mut hashes_sizes_timestamps = self
-            .fee_to_hashes_sizes_timestamps
-            .get(txn, &fee)
-            .into_diagnostic()?
-            .unwrap_or(vec![]);
-        hashes_sizes_timestamps.push((transaction_hash, transaction_size as u32, timestamp));
-        // Sort the vector in ascending order by transaction size in bytes and by timestamp.
-        //
-        // Smallest and oldest transactions would come first.
-        hashes_sizes_timestamps.sort_unstable_by_key(|(_, size, timestamp)| (*size, *timestamp));
-        self.fee_to_hashes_sizes_timestamps
-            .put(txn, &fee, &hashes_sizes_timestamps)
-            .into_diagnostic()?;
         Ok(())
     }
 
-    fn remove(&self, txn: &mut RwTxn, transaction_hash: &[u8; HASH_LENGTH]) -> Result<()> {
-        let (_, fee, _) = self
+    /// Checks that a transaction can replace the mempool transactions it conflicts with and their
+    /// descendants, in the style of BIP125.
+    ///
+    /// The replacement must pay a strictly higher fee than all replaced transactions together, so
+    /// that the miner doesn't lose out, and a strictly higher fee rate than the replaced
+    /// transactions together, so that it doesn't take up more block space for the same fee. On top
+    /// of the replaced fee it must pay MIN_RELAY_FEE_RATE for each of its own bytes, so that
+    /// every replacement pays for the bandwidth it takes to relay it.
+    fn check_replacement(
+        &self,
+        txn: &RoTxn,
+        replaced: &[[u8; HASH_LENGTH]],
+        fee: u64,
+        size: usize,
+    ) -> Result<()> {
+        if replaced.len() > MAX_REPLACEMENTS {
+            return Err(TransactionError::TooManyReplacements {
+                count: replaced.len(),
+                limit: MAX_REPLACEMENTS,
+            }
+            .into());
+        }
+        let mut replaced_fee = 0;
+        let mut replaced_size = 0;
+        for transaction_hash in replaced {
+            let (transaction, fee, _timestamp) = self
+                .hash_to_transaction_fee_timestamp
+                .get(txn, transaction_hash)
+                .into_diagnostic()?
+                .ok_or(miette!("mempool transaction doesn't exist"))?;
+            replaced_fee += fee;
+            replaced_size += bincode::serialize(&transaction).into_diagnostic()?.len();
+        }
+        if fee <= replaced_fee {
+            return Err(TransactionError::ReplacementFeeTooLow { fee, replaced_fee }.into());
+        }
+        // fee / size > replaced_fee / replaced_size, without rounding.
+        if fee as u128 * replaced_size as u128 <= replaced_fee as u128 * size as u128 {
+            return Err(TransactionError::ReplacementFeeRateTooLow {
+                fee,
+                size,
+                replaced_fee,
+                replaced_size,
+            }
+            .into());
+        }
+        let relay_fee = size as u64 * MIN_RELAY_FEE_RATE;
+        if fee < replaced_fee.saturating_add(relay_fee) {
+            return Err(TransactionError::ReplacementRelayFeeTooLow {
+                fee,
+                replaced_fee,
+                relay_fee,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Checks that a new transaction keeps every chain of mempool transactions it becomes part of
+    /// within MAX_CHAIN_LEN and MAX_CHAIN_SIZE, both for itself with its ancestors, and for each
+    /// ancestor with its descendants.
+    ///
+    /// Transactions that the new transaction replaces don't count.
+    fn check_chain_limits(
+        &self,
+        txn: &RoTxn,
+        ancestors: &[[u8; HASH_LENGTH]],
+        replaced: &[[u8; HASH_LENGTH]],
+        size: usize,
+    ) -> Result<()> {
+        let count = ancestors.len() + 1;
+        if count > MAX_CHAIN_LEN {
+            return Err(TransactionError::TooManyAncestors {
+                count,
+                limit: MAX_CHAIN_LEN,
+            }
+            .into());
+        }
+        let mut ancestors_size = size;
+        for ancestor in ancestors {
+            ancestors_size += self.get_size(txn, ancestor)?;
+            // The ancestor itself is among its descendants, and the new transaction isn't yet.
+            let mut descendants_count = 1;
+            let mut descendants_size = size;
+            for transaction_hash in self.get_with_descendants(txn, &[*ancestor])? {
+                if !replaced.contains(&transaction_hash) {
+                    descendants_count += 1;
+                    descendants_size += self.get_size(txn, &transaction_hash)?;
+                }
+            }
+            if descendants_count > MAX_CHAIN_LEN {
+                return Err(TransactionError::TooManyDescendants {
+                    transaction_hash: *ancestor,
+                    count: descendants_count,
+                    limit: MAX_CHAIN_LEN,
+                }
+                .into());
+            }
+            if descendants_size > MAX_CHAIN_SIZE {
+                return Err(TransactionError::DescendantsTooLarge {
+                    transaction_hash: *ancestor,
+                    size: descendants_size,
+                    limit: MAX_CHAIN_SIZE,
+                }
+                .into());
+            }
+        }
+        if ancestors_size > MAX_CHAIN_SIZE {
+            return Err(TransactionError::AncestorsTooLarge {
+                size: ancestors_size,
+                limit: MAX_CHAIN_SIZE,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn get_size(&self, txn: &RoTxn, transaction_hash: &[u8; HASH_LENGTH]) -> Result<usize> {
+        let (transaction, _fee, _timestamp) = self
             .hash_to_transaction_fee_timestamp
             .get(txn, transaction_hash)
             .into_diagnostic()?
             .ok_or(miette!("mempool transaction doesn't exist"))?;
+        let size = bincode::serialize(&transaction).into_diagnostic()?.len();
+        Ok(size)
+    }
+
+    /// Removes a transaction together with its descendants, which can't be included in a block
+    /// without it.
+    pub fn remove_with_descendants(
+        &self,
+        txn: &mut RwTxn,
+        transaction_hash: &[u8; HASH_LENGTH],
+    ) -> Result<()> {
+        for transaction_hash in self.get_with_descendants(txn, &[*transaction_hash])? {
+            self.remove(txn, &transaction_hash)?;
+        }
+        Ok(())
+    }
+
+    /// Removes a transaction, if it is in the mempool.
+    pub fn remove(&self, txn: &mut RwTxn, transaction_hash: &[u8; HASH_LENGTH]) -> Result<()> {
+        let Some((transaction, _fee, _timestamp)) = self
+            .hash_to_transaction_fee_timestamp
+            .get(txn, transaction_hash)
+            .into_diagnostic()?
+        else {
+            return Ok(());
+        };
+        for input in &transaction.transaction.inputs {
+            self.spent_outpoints.delete(txn, input).into_diagnostic()?;
+        }
+        for input in &transaction.unconfirmed_inputs {
+            self.spent_unconfirmed_outpoints
+                .delete(txn, input)
+                .into_diagnostic()?;
+        }
         self.hash_to_transaction_fee_timestamp
             .delete(txn, transaction_hash)
             .into_diagnostic()?;
-        let hashes_sizes_timestamps = self
-            .fee_to_hashes_sizes_timestamps
-            .get(txn, &fee)
-            .into_diagnostic()?
-            .ok_or(miette!("mempool transaction index doesn't exist"))?;
-        let hashes_sizes_timestamps: Vec<_> = hashes_sizes_timestamps
-            .into_iter()
-            .filter(|(hash, _, _)| hash != transaction_hash)
-            .collect();
-        self.fee_to_hashes_sizes_timestamps
-            .put(txn, &fee, &hashes_sizes_timestamps)
-            .into_diagnostic()?;
         Ok(())
     }
+
+    /// Raw contents of every database, for exact comparisons in tests.
+    #[cfg(test)]
+    pub fn dump(&self, txn: &RoTxn) -> Vec<crate::test_utils::Dump> {
+        use crate::test_utils::dump_db;
+        vec![
+            dump_db(txn, &self.hash_to_transaction_fee_timestamp),
+            dump_db(txn, &self.spent_outpoints),
+            dump_db(txn, &self.spent_unconfirmed_outpoints),
+        ]
+    }
+}
+
+/// Transaction together with its ancestors that weren't selected yet, as a candidate for
+/// [`Mempool::collect_transactions`].
+#[derive(Debug, Eq, PartialEq)]
+struct Package {
+    hash: [u8; HASH_LENGTH],
+    fee: u64,
+    size: usize,
+    timestamp: u64,
+}
+
+impl Ord for Package {
+    /// Highest fee rate first, then smallest, then oldest, by ordering the best package greatest.
+    ///
+    /// Fee rates are compared by cross multiplying, to avoid rounding.
+    fn cmp(&self, other: &Self) -> Ordering {
+        let fee_rate = self.fee as u128 * other.size as u128;
+        let other_fee_rate = other.fee as u128 * self.size as u128;
+        fee_rate
+            .cmp(&other_fee_rate)
+            .then(other.size.cmp(&self.size))
+            .then(other.timestamp.cmp(&self.timestamp))
+            .then(self.hash.cmp(&other.hash))
+    }
+}
+
+impl PartialOrd for Package {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_env, unsigned};
+    use cusf_sidechain_types::Transaction;
+
+    fn new_mempool() -> (tempfile::TempDir, Env, Mempool) {
+        let (dir, env) = new_env(Mempool::NUM_DBS);
+        let mempool = Mempool::new(&env).unwrap();
+        (dir, env, mempool)
+    }
+
+    fn spend(
+        inputs: Vec<OutPoint>,
+        unconfirmed_inputs: Vec<UnconfirmedOutPoint>,
+        value: u64,
+    ) -> AuthorizedTransaction {
+        let outputs = vec![Output::Regular {
+            address: [1; 20],
+            value,
+        }];
+        AuthorizedTransaction {
+            unconfirmed_inputs,
+            ..unsigned(Transaction { inputs, outputs })
+        }
+    }
+
+    fn spend_deposit(sequence_number: u64, value: u64) -> AuthorizedTransaction {
+        spend(vec![OutPoint::Deposit { sequence_number }], vec![], value)
+    }
+
+    fn spend_unconfirmed(parent: &AuthorizedTransaction, value: u64) -> AuthorizedTransaction {
+        let input = UnconfirmedOutPoint {
+            transaction_hash: parent.hash(),
+            output_number: 0,
+        };
+        spend(vec![], vec![input], value)
+    }
+
+    fn submit(
+        mempool: &Mempool,
+        txn: &mut RwTxn,
+        transaction: &AuthorizedTransaction,
+        fee: u64,
+    ) -> Result<()> {
+        let spent = SpentOutputs {
+            outpoints: transaction.transaction.inputs.clone(),
+            unconfirmed_outpoints: transaction.unconfirmed_inputs.clone(),
+        };
+        mempool.submit_transaction(txn, transaction, fee, &spent)
+    }
+
+    #[test]
+    fn replacement_pays_relay_fee_for_its_size() {
+        let (_dir, env, mempool) = new_mempool();
+        let mut txn = env.write_txn().unwrap();
+        submit(&mempool, &mut txn, &spend_deposit(0, 1), 1_000).unwrap();
+        let replacement = spend_deposit(0, 2);
+        let size = bincode::serialize(&replacement).unwrap().len() as u64;
+        let relay_fee = size * MIN_RELAY_FEE_RATE;
+        let error = submit(&mempool, &mut txn, &replacement, 1_000 + relay_fee - 1).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(TransactionError::ReplacementRelayFeeTooLow { .. })
+        ));
+        submit(&mempool, &mut txn, &replacement, 1_000 + relay_fee).unwrap();
+        assert!(!mempool.contains(&txn, &spend_deposit(0, 1).hash()).unwrap());
+    }
+
+    #[test]
+    fn chains_are_limited_and_replaced_with_their_descendants() {
+        let (_dir, env, mempool) = new_mempool();
+        let mut txn = env.write_txn().unwrap();
+        let mut root = spend_deposit(0, 1_000_000);
+        root.transaction.outputs.push(Output::Regular {
+            address: [1; 20],
+            value: 1,
+        });
+        let mut chain = vec![root];
+        while chain.len() < MAX_CHAIN_LEN {
+            chain.push(spend_unconfirmed(chain.last().unwrap(), 1_000_000));
+        }
+        for transaction in &chain {
+            submit(&mempool, &mut txn, transaction, 1_000).unwrap();
+        }
+        let too_long = spend_unconfirmed(chain.last().unwrap(), 1_000_000);
+        let error = submit(&mempool, &mut txn, &too_long, 1_000).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(TransactionError::TooManyAncestors { .. })
+        ));
+        // A second child of the root would give it too many descendants.
+        let sibling_input = UnconfirmedOutPoint {
+            transaction_hash: chain[0].hash(),
+            output_number: 1,
+        };
+        let sibling = spend(vec![], vec![sibling_input], 1);
+        let error = submit(&mempool, &mut txn, &sibling, 1_000).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(TransactionError::TooManyDescendants { .. })
+        ));
+        // Replacing the root replaces the whole chain, so it has to pay for all of it.
+        let replacement = spend_deposit(0, 1);
+        let chain_fee = 1_000 * MAX_CHAIN_LEN as u64;
+        let error = submit(&mempool, &mut txn, &replacement, chain_fee).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(TransactionError::ReplacementFeeTooLow { .. })
+        ));
+        submit(&mempool, &mut txn, &replacement, chain_fee + 10_000).unwrap();
+        let transactions = mempool.get_transactions(&txn).unwrap();
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].0, replacement.hash());
+    }
 }
@@ -1,17 +1,36 @@
 mod archive;
+mod codec;
+pub mod error;
 mod mempool;
+mod utxo_set_hash;
 mod utxos;
 
 use archive::Archive;
 use bip300301_enforcer_proto::validator::Deposit;
 use cusf_sidechain_types::{
-    Header, MainBlock, OutPoint, Output, Transaction, WithdrawalBundleEventType, HASH_LENGTH,
+    MainBlock, OutPoint, Output, WithdrawalBundleEventType, BLOCK_SIZE_LIMIT, HASH_LENGTH,
 };
-use heed::{Env, EnvOpenOptions};
-use mempool::Mempool;
+use error::{BlockError, TransactionError};
+use heed::types::SerdeBincode;
+use heed::{Database, Env, EnvOpenOptions, RoTxn, RwTxn};
+use mempool::{Mempool, SpentOutputs};
 use miette::{miette, IntoDiagnostic, Result};
-use std::{collections::HashMap, path::Path};
-use utxos::Utxos;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+use utxos::{checked_value_sum, MainBlockUndo, UnitKey, Utxos, MAX_OUTPUTS_LEN};
+
+pub use utxos::{BundlePreview, WithdrawalState, WithdrawalTransition};
+
+use crate::authorization::{self, AuthorizedTransaction, UnconfirmedOutPoint};
+use crate::block::{self, Header, TransactionProof};
+use crate::withdrawal;
+
+/// Version of the on-disk data, bumped whenever existing data has to be migrated.
+///
+/// Data without a version was written before versions existed, see [`State::migrate`].
+const VERSION: u32 = 1;
 
 #[derive(Clone)]
 pub struct State {
@@ -24,18 +43,47 @@ pub struct State {
 impl State {
     pub fn new(datadir: &Path) -> Result<Self> {
         let env = EnvOpenOptions::new()
-            .max_dbs(Mempool::NUM_DBS + Archive::NUM_DBS + Utxos::NUM_DBS)
+            // One more database for the version.
+            .max_dbs(Mempool::NUM_DBS + Archive::NUM_DBS + Utxos::NUM_DBS + 1)
             .open(datadir.join("data.mdb"))
             .into_diagnostic()?;
         let mempool = Mempool::new(&env)?;
         let archive = Archive::new(&env)?;
         let utxos = Utxos::new(&env)?;
-        Ok(Self {
+        let state = Self {
             env,
             mempool,
             archive,
             utxos,
-        })
+        };
+        state.migrate()?;
+        Ok(state)
+    }
+
+    /// Brings data written by older versions up to date, once.
+    ///
+    /// Version 0 is data written before versions existed. No undo data was recorded for the side
+    /// and main blocks connected back then, so they can't be disconnected.
+    fn migrate(&self) -> Result<()> {
+        let version_db: Database<SerdeBincode<UnitKey>, SerdeBincode<u32>> = self
+            .env
+            .create_database(Some("state_version"))
+            .into_diagnostic()?;
+        let mut txn = self.env.write_txn().into_diagnostic()?;
+        let version = version_db
+            .get(&txn, &UnitKey)
+            .into_diagnostic()?
+            .unwrap_or(0);
+        if version < 1 {
+            self.archive.migrate_from_v0(&mut txn)?;
+            self.mempool.migrate_from_v0(&mut txn)?;
+            self.utxos.rebuild_utxo_set_hash(&mut txn)?;
+        }
+        version_db
+            .put(&mut txn, &UnitKey, &VERSION)
+            .into_diagnostic()?;
+        txn.commit().into_diagnostic()?;
+        Ok(())
     }
 
     pub fn is_clean(&self) -> Result<bool> {
@@ -58,32 +106,216 @@ impl State {
     pub fn get_withdrawal_bundle(&self) -> Result<bitcoin::Transaction> {
         let mut txn = self.env.write_txn().into_diagnostic()?;
         self.utxos.collect_withdrawals(&mut txn)?;
+        self.evict_invalid_transactions(&mut txn)?;
         let bundle = self.utxos.get_withdrawal_bundle(&txn)?;
-        // txn.commit().into_diagnostic()?;
+        txn.commit().into_diagnostic()?;
         Ok(bundle)
     }
 
+    pub fn preview_withdrawal_bundle(&self) -> Result<BundlePreview> {
+        let txn = self.env.read_txn().into_diagnostic()?;
+        let preview = self.utxos.preview_withdrawal_bundle(&txn)?;
+        Ok(preview)
+    }
+
+    pub fn get_withdrawal_status(
+        &self,
+        outpoint: &OutPoint,
+    ) -> Result<Option<Vec<WithdrawalTransition>>> {
+        let txn = self.env.read_txn().into_diagnostic()?;
+        let status = self.utxos.get_withdrawal_status(&txn, outpoint)?;
+        Ok(status)
+    }
+
+    pub fn get_transaction_proof(
+        &self,
+        transaction_number: u64,
+    ) -> Result<Option<TransactionProof>> {
+        let txn = self.env.read_txn().into_diagnostic()?;
+        let proof = self
+            .archive
+            .get_transaction_proof(&txn, transaction_number)?;
+        Ok(proof)
+    }
+
+    pub fn get_transaction_proof_by_hash(
+        &self,
+        transaction_hash: &[u8; HASH_LENGTH],
+    ) -> Result<Option<TransactionProof>> {
+        let txn = self.env.read_txn().into_diagnostic()?;
+        let Some(transaction_number) = self
+            .archive
+            .get_transaction_number(&txn, transaction_hash)?
+        else {
+            return Ok(None);
+        };
+        let proof = self
+            .archive
+            .get_transaction_proof(&txn, transaction_number)?;
+        Ok(proof)
+    }
+
+    pub fn get_utxo_set_commitment(&self, block_height: u32) -> Result<Option<[u8; HASH_LENGTH]>> {
+        let txn = self.env.read_txn().into_diagnostic()?;
+        let commitment = self.utxos.get_utxo_set_commitment(&txn, block_height)?;
+        Ok(commitment)
+    }
+
     pub fn get_main_chain_tip(&self) -> Result<[u8; HASH_LENGTH]> {
         let txn = self.env.read_txn().into_diagnostic()?;
         let chain_tip = self.utxos.get_main_chain_tip(&txn)?;
         Ok(chain_tip)
     }
 
-    pub fn collect_transactions(&self) -> Result<Vec<Transaction>> {
+    pub fn collect_transactions(&self) -> Result<Vec<AuthorizedTransaction>> {
         let txn = self.env.read_txn().into_diagnostic()?;
         let transactions = self.mempool.collect_transactions(&txn)?;
         Ok(transactions)
     }
 
-    pub fn submit_transaction(&self, transaction: &Transaction) -> Result<()> {
+    /// Adds a transaction to the mempool, replacing conflicting mempool transactions if it pays
+    /// enough.
+    ///
+    /// Unconfirmed inputs can spend outputs of mempool transactions, within the mempool's limits on
+    /// chains of unconfirmed transactions, or of transactions that were included in a block since.
+    pub fn submit_transaction(&self, transaction: &AuthorizedTransaction) -> Result<()> {
         let mut txn = self.env.write_txn().into_diagnostic()?;
-        let fee = self.utxos.get_transaction_fee(&txn, transaction)?;
-        self.mempool
-            .submit_transaction(&mut txn, transaction, fee)?;
+        self.add_to_mempool(&mut txn, transaction)?;
+        txn.commit().into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Submits a transaction that cancels withdrawals before they are locked in a bundle, by
+    /// spending them back to regular outputs.
+    ///
+    /// Any transaction spending an unlocked withdrawal cancels it, but this only accepts
+    /// transactions that do nothing else, so a wallet can't cancel a withdrawal by accident, or
+    /// cancel one that is already locked and would be paid out anyway.
+    pub fn cancel_withdrawal(&self, transaction: &AuthorizedTransaction) -> Result<()> {
+        let mut txn = self.env.write_txn().into_diagnostic()?;
+        for input in &transaction.transaction.inputs {
+            if !self.utxos.is_unlocked_withdrawal(&txn, input)? {
+                return Err(TransactionError::NotUnlockedWithdrawal(input.clone()).into());
+            }
+        }
+        if let Some(input) = transaction.unconfirmed_inputs.first() {
+            return Err(TransactionError::UnconfirmedCancellationInput(input.clone()).into());
+        }
+        for (index, output) in transaction.transaction.outputs.iter().enumerate() {
+            if !matches!(output, Output::Regular { .. }) {
+                return Err(TransactionError::NonRegularCancellationOutput(index).into());
+            }
+        }
+        self.add_to_mempool(&mut txn, transaction)?;
         txn.commit().into_diagnostic()?;
         Ok(())
     }
 
+    fn add_to_mempool(&self, txn: &mut RwTxn, transaction: &AuthorizedTransaction) -> Result<()> {
+        let (fee, spent) = self.check_transaction(txn, transaction)?;
+        self.mempool
+            .submit_transaction(txn, transaction, fee, &spent)?;
+        Ok(())
+    }
+
+    /// Checks a transaction against the current utxo set and mempool, and returns its fee and the
+    /// outputs it spends.
+    fn check_transaction(
+        &self,
+        txn: &RoTxn,
+        transaction: &AuthorizedTransaction,
+    ) -> Result<(u64, SpentOutputs)> {
+        withdrawal::check_withdrawal_destinations(transaction)?;
+        let outputs = &transaction.transaction.outputs;
+        if outputs.len() > MAX_OUTPUTS_LEN {
+            return Err(TransactionError::TooManyOutputs {
+                len: outputs.len(),
+                limit: MAX_OUTPUTS_LEN,
+            }
+            .into());
+        }
+        let (spent_outputs, spent) = self.get_spent_outputs(txn, transaction)?;
+        let value_in = checked_value_sum(&spent_outputs).ok_or(TransactionError::ValueOverflow)?;
+        let value_out = checked_value_sum(&transaction.transaction.outputs)
+            .ok_or(TransactionError::ValueOverflow)?;
+        if value_in < value_out {
+            return Err(TransactionError::Overspend {
+                value_in,
+                value_out,
+            }
+            .into());
+        }
+        let addresses: Vec<_> = spent_outputs.iter().map(Output::address).collect();
+        authorization::is_authorized(transaction, &addresses)?;
+        Ok((value_in - value_out, spent))
+    }
+
+    /// Returns the outputs a transaction spends, in the same order as its authorizations.
+    ///
+    /// An unconfirmed input spends an output of a mempool transaction, or of a transaction that
+    /// was included in a block since, in which case the output is spent from the utxo set.
+    fn get_spent_outputs(
+        &self,
+        txn: &RoTxn,
+        transaction: &AuthorizedTransaction,
+    ) -> Result<(Vec<Output>, SpentOutputs)> {
+        let mut spent_outputs = vec![];
+        let mut spent = SpentOutputs::default();
+        let mut spent_outpoints = HashSet::new();
+        let mut spent_unconfirmed_outpoints = HashSet::new();
+        for input in &transaction.transaction.inputs {
+            if !spent_outpoints.insert(input.clone()) {
+                return Err(TransactionError::DoubleSpend(input.clone()).into());
+            }
+            spent_outputs.push(self.utxos.get_spendable_output(txn, input)?);
+            spent.outpoints.push(input.clone());
+            if let OutPoint::Regular {
+                transaction_number,
+                output_number,
+            } = input
+            {
+                if let Some(transaction_hash) = self
+                    .archive
+                    .get_transaction_hash(txn, *transaction_number)?
+                {
+                    spent.unconfirmed_outpoints.push(UnconfirmedOutPoint {
+                        transaction_hash,
+                        output_number: *output_number,
+                    });
+                }
+            }
+        }
+        for input in &transaction.unconfirmed_inputs {
+            if !spent_unconfirmed_outpoints.insert(input.clone()) {
+                return Err(TransactionError::UnconfirmedDoubleSpend(input.clone()).into());
+            }
+            let transaction_number = self
+                .archive
+                .get_transaction_number(txn, &input.transaction_hash)?;
+            let output = match transaction_number {
+                Some(transaction_number) => {
+                    let outpoint = OutPoint::Regular {
+                        transaction_number,
+                        output_number: input.output_number,
+                    };
+                    if !spent_outpoints.insert(outpoint.clone()) {
+                        return Err(TransactionError::DoubleSpend(outpoint).into());
+                    }
+                    let output = self.utxos.get_spendable_output(txn, &outpoint)?;
+                    spent.outpoints.push(outpoint);
+                    output
+                }
+                None => self
+                    .mempool
+                    .get_output(txn, input)?
+                    .ok_or(TransactionError::MissingUnconfirmedInput(input.clone()))?,
+            };
+            spent_outputs.push(output);
+            spent.unconfirmed_outpoints.push(input.clone());
+        }
+        Ok((spent_outputs, spent))
+    }
+
     pub fn load_deposits(
         &self,
         deposits: &[Deposit],
@@ -91,6 +323,7 @@ impl State {
         main_chain_tip: &[u8; HASH_LENGTH],
     ) -> Result<()> {
         let mut txn = self.env.write_txn().into_diagnostic()?;
+        let mut utxos = vec![];
         for deposit in deposits {
             let outpoint = OutPoint::Deposit {
                 sequence_number: deposit.sequence_number,
@@ -99,9 +332,10 @@ impl State {
                 address: deposit.address.clone().try_into().unwrap(),
                 value: deposit.value,
             };
-            self.utxos.add_utxo(&mut txn, &outpoint, &output)?;
             println!("{outpoint} -> {output}");
+            utxos.push((outpoint, output));
         }
+        self.utxos.add_utxos(&mut txn, &utxos)?;
         self.utxos
             .set_main_block_height(&mut txn, main_block_height)?;
         self.utxos.set_main_chain_tip(&mut txn, main_chain_tip)?;
@@ -109,23 +343,107 @@ impl State {
         Ok(())
     }
 
+    /// Checks everything that makes a block valid, without writing anything.
+    ///
+    /// `resolved_transactions` are the transactions as returned by [`State::resolve_inputs`].
     fn is_valid(
         &self,
+        txn: &RoTxn,
         header: &Header,
         coinbase: &[Output],
-        transactions: &[Transaction],
+        transactions: &[AuthorizedTransaction],
+        resolved_transactions: &[AuthorizedTransaction],
     ) -> Result<()> {
-        todo!();
+        self.archive.validate_header(txn, header)?;
+        let merkle_root = block::compute_merkle_root(coinbase, transactions);
+        if header.merkle_root != merkle_root {
+            return Err(BlockError::WrongMerkleRoot {
+                expected: merkle_root,
+                actual: header.merkle_root,
+            }
+            .into());
+        }
+        let transactions_bytes = bincode::serialize(transactions).into_diagnostic()?;
+        if transactions_bytes.len() > BLOCK_SIZE_LIMIT {
+            return Err(BlockError::Oversize {
+                size: transactions_bytes.len(),
+                limit: BLOCK_SIZE_LIMIT,
+            }
+            .into());
+        }
+        for (index, transaction) in transactions.iter().enumerate() {
+            withdrawal::check_withdrawal_destinations(transaction)
+                .map_err(|source| BlockError::InvalidTransaction { index, source })?;
+        }
+        self.utxos.validate(txn, coinbase, resolved_transactions)?;
+        let addresses = self
+            .utxos
+            .extract_input_addresses(txn, resolved_transactions)?;
+        authorization::verify_authorizations(transactions, &addresses)?;
+        Ok(())
+    }
+
+    /// Returns copies of block transactions, with their unconfirmed inputs turned into the regular
+    /// inputs they spend, for validating and updating the utxo set.
+    ///
+    /// An unconfirmed input spends an output of a transaction in an earlier block, or of an
+    /// earlier transaction in the same block, which is numbered like [`Utxos::connect`] numbers it.
+    /// The copies hash differently, so blocks, the archive and the mempool keep the originals.
+    fn resolve_inputs(
+        &self,
+        txn: &RoTxn,
+        transactions: &[AuthorizedTransaction],
+    ) -> Result<Vec<AuthorizedTransaction>> {
+        let mut transaction_number = self.utxos.get_next_transaction_number(txn)?;
+        let mut block_transaction_numbers = HashMap::new();
+        let mut resolved_transactions = vec![];
+        for (index, transaction) in transactions.iter().enumerate() {
+            let mut resolved_transaction = transaction.clone();
+            for input in resolved_transaction.unconfirmed_inputs.drain(..) {
+                let spent_transaction_number =
+                    match block_transaction_numbers.get(&input.transaction_hash) {
+                        Some(transaction_number) => Some(*transaction_number),
+                        None => self
+                            .archive
+                            .get_transaction_number(txn, &input.transaction_hash)?,
+                    };
+                let Some(spent_transaction_number) = spent_transaction_number else {
+                    return Err(BlockError::InvalidTransaction {
+                        index,
+                        source: TransactionError::MissingUnconfirmedInput(input),
+                    }
+                    .into());
+                };
+                resolved_transaction
+                    .transaction
+                    .inputs
+                    .push(OutPoint::Regular {
+                        transaction_number: spent_transaction_number,
+                        output_number: input.output_number,
+                    });
+            }
+            block_transaction_numbers.insert(transaction.hash(), transaction_number);
+            transaction_number += 1;
+            resolved_transactions.push(resolved_transaction);
+        }
+        Ok(resolved_transactions)
     }
 
     pub fn connect(
         &self,
         header: Header,
         coinbase: &[Output],
-        transactions: &[Transaction],
+        transactions: &[AuthorizedTransaction],
     ) -> Result<()> {
         let mut txn = self.env.write_txn().into_diagnostic()?;
-        self.archive.validate_header(&txn, &header)?;
+        let resolved_transactions = self.resolve_inputs(&txn, transactions)?;
+        self.is_valid(
+            &txn,
+            &header,
+            coinbase,
+            transactions,
+            &resolved_transactions,
+        )?;
         let block_height = self
             .archive
             .get_chain_tip(&txn)?
@@ -134,48 +452,711 @@ impl State {
         self.archive
             .connect(&mut txn, header, coinbase, transactions)?;
         self.utxos
-            .connect(&mut txn, block_height, coinbase, transactions)?;
+            .connect(&mut txn, block_height, coinbase, &resolved_transactions)?;
         self.mempool.connect(&mut txn, transactions)?;
+        self.evict_invalid_transactions(&mut txn)?;
         txn.commit().into_diagnostic()?;
         Ok(())
     }
 
-    fn disconnect(&self, number: u32) -> Result<()> {
-        todo!();
+    /// Removes mempool transactions that can no longer be included in a block, because they spend
+    /// outputs that were spent by a block, disappeared with a main block, or were locked in a
+    /// withdrawal bundle.
+    ///
+    /// Every transaction is checked again like on admission, since a reorg can put a different
+    /// output at an outpoint it spends, such as a deposit or coinbase output with another address
+    /// or value. Transactions whose fee changed are evicted too, since the mempool indexes them by
+    /// the fee they were admitted with.
+    ///
+    /// A reorg can also make two mempool transactions spend the same output, when one refers to it
+    /// by transaction number and the other by transaction hash, so the later one is evicted.
+    /// Descendants are evicted together with the transaction whose outputs they spend.
+    fn evict_invalid_transactions(&self, txn: &mut RwTxn) -> Result<()> {
+        let mut spent_outpoints = HashSet::new();
+        let mut spent_unconfirmed_outpoints = HashSet::new();
+        for (transaction_hash, transaction, fee) in self.mempool.get_transactions(txn)? {
+            // Evicted as a descendant of an earlier transaction.
+            if !self.mempool.contains(txn, &transaction_hash)? {
+                continue;
+            }
+            match self.check_transaction(txn, &transaction) {
+                Ok((current_fee, spent)) if current_fee == fee => {
+                    let is_double_spend = spent
+                        .outpoints
+                        .iter()
+                        .any(|outpoint| spent_outpoints.contains(outpoint))
+                        || spent
+                            .unconfirmed_outpoints
+                            .iter()
+                            .any(|outpoint| spent_unconfirmed_outpoints.contains(outpoint));
+                    if is_double_spend {
+                        self.mempool
+                            .remove_with_descendants(txn, &transaction_hash)?;
+                        continue;
+                    }
+                    spent_outpoints.extend(spent.outpoints);
+                    spent_unconfirmed_outpoints.extend(spent.unconfirmed_outpoints);
+                }
+                Ok(_) => {
+                    self.mempool
+                        .remove_with_descendants(txn, &transaction_hash)?;
+                }
+                Err(err) if err.downcast_ref::<TransactionError>().is_some() => {
+                    self.mempool
+                        .remove_with_descendants(txn, &transaction_hash)?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
     }
 
-    pub fn connect_main_block(&self, block: &MainBlock) -> Result<()> {
+    /// Disconnect number latest blocks.
+    ///
+    /// Transactions of the disconnected blocks are put back into the mempool.
+    pub fn disconnect(&self, number: u32) -> Result<()> {
         let mut txn = self.env.write_txn().into_diagnostic()?;
-        for deposit in &block.deposits {
-            let (outpoint, output) = deposit;
-            self.utxos.add_utxo(&mut txn, outpoint, output)?;
+        self.disconnect_blocks(&mut txn, number)?;
+        self.evict_invalid_transactions(&mut txn)?;
+        txn.commit().into_diagnostic()?;
+        Ok(())
+    }
+
+    fn disconnect_blocks(&self, txn: &mut RwTxn, number: u32) -> Result<()> {
+        let side_block_height = self.utxos.get_side_block_height(txn)?;
+        if number > side_block_height {
+            return Err(miette!(
+                "can't disconnect {number} blocks, side chain height is {side_block_height}"
+            ));
         }
-        if let Some(withdrawal_bundle_event) = &block.withdrawal_bundle_event {
-            match withdrawal_bundle_event.withdrawal_bundle_event_type {
-                WithdrawalBundleEventType::Submitted => {
-                    self.utxos
-                        .submit_bundle(&mut txn, &withdrawal_bundle_event.m6id)?;
-                }
-                WithdrawalBundleEventType::Succeded => {
-                    self.utxos
-                        .succeed_bundle(&mut txn, &withdrawal_bundle_event.m6id)?;
-                }
-                WithdrawalBundleEventType::Failed => {
-                    self.utxos
-                        .fail_bundle(&mut txn, &withdrawal_bundle_event.m6id)?;
-                }
+        for block_height in (side_block_height - number + 1..=side_block_height).rev() {
+            self.utxos.disconnect(txn, block_height)?;
+        }
+        let transactions = self.archive.disconnect(txn, number)?;
+        // Transactions are put back in block order, so unconfirmed inputs find the disconnected
+        // transactions they spend in the mempool again. Transactions that are no longer valid,
+        // such as ones spending outputs of other disconnected transactions by transaction number,
+        // or ones conflicting with a transaction that was submitted since, are dropped.
+        for transaction in &transactions {
+            match self.add_to_mempool(txn, transaction) {
+                Ok(()) => {}
+                Err(err) if err.downcast_ref::<TransactionError>().is_some() => {}
+                Err(err) => return Err(err),
             }
         }
-        let mut main_block_height = self.utxos.get_main_block_height(&txn)?;
-        main_block_height += 1;
+        Ok(())
+    }
+
+    pub fn connect_main_block(&self, block: &MainBlock) -> Result<()> {
+        let mut txn = self.env.write_txn().into_diagnostic()?;
+        let prev_main_block_height = self.utxos.get_main_block_height(&txn)?;
+        let main_block_height = prev_main_block_height + 1;
         if main_block_height != block.block_height {
             return Err(miette!("invalid main block height"));
         }
-        self.archive.add_bmm_hashes(&mut txn, &block.bmm_hashes)?;
+        let mut undo = MainBlockUndo {
+            prev_main_block_height,
+            prev_main_chain_tip: self.utxos.get_main_chain_tip(&txn)?,
+            side_block_height: self.utxos.get_side_block_height(&txn)?,
+            deposits: vec![],
+            bmm_hashes: vec![],
+            bundle_event: None,
+            expired_bundle: None,
+        };
+        self.utxos.add_utxos(&mut txn, &block.deposits)?;
+        for (outpoint, _output) in &block.deposits {
+            undo.deposits.push(outpoint.clone());
+        }
+        if let Some(withdrawal_bundle_event) = &block.withdrawal_bundle_event {
+            let bundle_event = match withdrawal_bundle_event.withdrawal_bundle_event_type {
+                WithdrawalBundleEventType::Submitted => self.utxos.submit_bundle(
+                    &mut txn,
+                    &withdrawal_bundle_event.m6id,
+                    main_block_height,
+                )?,
+                WithdrawalBundleEventType::Succeded => self.utxos.succeed_bundle(
+                    &mut txn,
+                    &withdrawal_bundle_event.m6id,
+                    main_block_height,
+                )?,
+                WithdrawalBundleEventType::Failed => self.utxos.fail_bundle(
+                    &mut txn,
+                    &withdrawal_bundle_event.m6id,
+                    main_block_height,
+                )?,
+            };
+            undo.bundle_event = Some(bundle_event);
+        }
+        undo.expired_bundle = self.utxos.expire_bundle(&mut txn, main_block_height)?;
+        undo.bmm_hashes = self.archive.add_bmm_hashes(&mut txn, &block.bmm_hashes)?;
         self.utxos
             .set_main_block_height(&mut txn, main_block_height)?;
         self.utxos.set_main_chain_tip(&mut txn, &block.block_hash)?;
+        self.utxos
+            .put_main_block_undo(&mut txn, &block.block_hash, &undo)?;
         txn.commit().into_diagnostic()?;
         Ok(())
     }
+
+    /// Reverts [`State::connect_main_block`] for the main chain tip.
+    ///
+    /// Side blocks that are no longer valid without the main block, because their BMM commitment
+    /// disappeared or because they spend one of its deposits, are disconnected as well.
+    pub fn disconnect_main_block(&self, block_hash: &[u8; HASH_LENGTH]) -> Result<()> {
+        let mut txn = self.env.write_txn().into_diagnostic()?;
+        let main_chain_tip = self.utxos.get_main_chain_tip(&txn)?;
+        if *block_hash != main_chain_tip {
+            return Err(miette!(
+                "main block {} is not the main chain tip",
+                hex::encode(block_hash)
+            ));
+        }
+        let undo = self.utxos.take_main_block_undo(&mut txn, block_hash)?;
+        self.archive.remove_bmm_hashes(&mut txn, &undo.bmm_hashes)?;
+        // Orphaned side blocks were connected after the main block, so bundles that lock their
+        // withdrawals were collected after it too, and can't have been submitted yet.
+        if let Some(block_number) = self.get_first_orphaned_block(&txn, &undo)? {
+            let side_block_height = self.utxos.get_side_block_height(&txn)?;
+            self.disconnect_blocks(&mut txn, side_block_height - block_number + 1)?;
+        }
+        if let Some(expired_bundle) = &undo.expired_bundle {
+            self.utxos.undo_bundle_event(&mut txn, expired_bundle)?;
+        }
+        if let Some(bundle_event) = &undo.bundle_event {
+            self.utxos.undo_bundle_event(&mut txn, bundle_event)?;
+        }
+        self.utxos.remove_utxos(&mut txn, &undo.deposits)?;
+        self.utxos
+            .set_main_block_height(&mut txn, undo.prev_main_block_height)?;
+        self.utxos
+            .set_main_chain_tip(&mut txn, &undo.prev_main_chain_tip)?;
+        self.evict_invalid_transactions(&mut txn)?;
+        txn.commit().into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Finds the first side block that depends on a main block that is being disconnected.
+    ///
+    /// Only side blocks connected after the main block can depend on it.
+    fn get_first_orphaned_block(&self, txn: &RoTxn, undo: &MainBlockUndo) -> Result<Option<u32>> {
+        let deposits: HashSet<&OutPoint> = undo.deposits.iter().collect();
+        let side_block_height = self.utxos.get_side_block_height(txn)?;
+        for block_number in undo.side_block_height + 1..=side_block_height {
+            let (header, transactions) = self
+                .archive
+                .get_block(txn, block_number)?
+                .ok_or(miette!("side block {block_number} doesn't exist"))?;
+            let is_bmm = self.archive.is_bmm_hash(txn, &header.hash())?;
+            let spends_deposit = transactions
+                .iter()
+                .flat_map(|transaction| &transaction.transaction.inputs)
+                .any(|input| deposits.contains(input));
+            if !is_bmm || spends_deposit {
+                return Ok(Some(block_number));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{authorize, Dump};
+    use cusf_sidechain_types::{Transaction, WithdrawalBundleEvent};
+    use ed25519_dalek::SigningKey;
+    use utxos::{BUNDLE_FAILURE_GRACE_WINDOW, MAX_PENDING_BUNDLE_AGE};
+
+    fn sign(signing_key: &SigningKey, sequence_number: u64, value: u64) -> AuthorizedTransaction {
+        let transaction = Transaction {
+            inputs: vec![OutPoint::Deposit { sequence_number }],
+            outputs: vec![Output::Regular {
+                address: authorization::get_address(&signing_key.verifying_key()),
+                value,
+            }],
+        };
+        authorize(signing_key, transaction, vec![])
+    }
+
+    /// Spends the first output of a transaction that may still be in the mempool.
+    fn sign_child(
+        signing_key: &SigningKey,
+        parent: &AuthorizedTransaction,
+        value: u64,
+    ) -> AuthorizedTransaction {
+        let transaction = Transaction {
+            inputs: vec![],
+            outputs: vec![Output::Regular {
+                address: authorization::get_address(&signing_key.verifying_key()),
+                value,
+            }],
+        };
+        let unconfirmed_inputs = vec![UnconfirmedOutPoint {
+            transaction_hash: parent.hash(),
+            output_number: 0,
+        }];
+        authorize(signing_key, transaction, unconfirmed_inputs)
+    }
+
+    /// State with three deposits of 100_000 owned by the signing key, in a main block that BMMs
+    /// `bmm_hashes`.
+    fn new_state(
+        signing_key: &SigningKey,
+        bmm_hashes: Vec<[u8; HASH_LENGTH]>,
+    ) -> (tempfile::TempDir, State) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("data.mdb")).unwrap();
+        let state = State::new(dir.path()).unwrap();
+        let address = authorization::get_address(&signing_key.verifying_key());
+        let deposits = (0..3)
+            .map(|sequence_number| {
+                let outpoint = OutPoint::Deposit { sequence_number };
+                let output = Output::Regular {
+                    address,
+                    value: 100_000,
+                };
+                (outpoint, output)
+            })
+            .collect();
+        let main_block = MainBlock {
+            block_height: 1,
+            block_hash: [1; HASH_LENGTH],
+            deposits,
+            withdrawal_bundle_event: None,
+            bmm_hashes,
+        };
+        state.connect_main_block(&main_block).unwrap();
+        (dir, state)
+    }
+
+    /// Spends a deposit to a withdrawal of 90_000, followed by `outputs`.
+    fn sign_withdrawal(
+        signing_key: &SigningKey,
+        sequence_number: u64,
+        outputs: Vec<Output>,
+    ) -> AuthorizedTransaction {
+        let withdrawal = Output::Withdrawal {
+            address: authorization::get_address(&signing_key.verifying_key()),
+            main_address: [3; 20],
+            value: 90_000,
+            fee: 1_000,
+        };
+        let transaction = Transaction {
+            inputs: vec![OutPoint::Deposit { sequence_number }],
+            outputs: [vec![withdrawal], outputs].concat(),
+        };
+        authorize(signing_key, transaction, vec![])
+    }
+
+    fn mempool_hashes(state: &State) -> HashSet<[u8; HASH_LENGTH]> {
+        let txn = state.env.read_txn().unwrap();
+        state
+            .mempool
+            .get_transactions(&txn)
+            .unwrap()
+            .into_iter()
+            .map(|(transaction_hash, _transaction, _fee)| transaction_hash)
+            .collect()
+    }
+
+    fn dump(state: &State) -> Vec<Dump> {
+        let txn = state.env.read_txn().unwrap();
+        [
+            state.utxos.dump(&txn),
+            state.archive.dump(&txn),
+            state.mempool.dump(&txn),
+        ]
+        .concat()
+    }
+
+    /// Main block at the height after the main chain tip, with a hash derived from its height.
+    fn next_main_block(
+        state: &State,
+        deposits: Vec<(OutPoint, Output)>,
+        event: Option<(WithdrawalBundleEventType, [u8; HASH_LENGTH])>,
+        bmm_hashes: Vec<[u8; HASH_LENGTH]>,
+    ) -> MainBlock {
+        let txn = state.env.read_txn().unwrap();
+        let block_height = state.utxos.get_main_block_height(&txn).unwrap() + 1;
+        let mut block_hash = [0; HASH_LENGTH];
+        block_hash[..4].copy_from_slice(&block_height.to_le_bytes());
+        MainBlock {
+            block_height,
+            block_hash,
+            deposits,
+            withdrawal_bundle_event: event.map(|(withdrawal_bundle_event_type, m6id)| {
+                WithdrawalBundleEvent {
+                    withdrawal_bundle_event_type,
+                    m6id,
+                }
+            }),
+            bmm_hashes,
+        }
+    }
+
+    /// Jumps to a main block height, instead of connecting every main block up to it.
+    fn skip_main_blocks(state: &State, main_block_height: u32) {
+        let mut txn = state.env.write_txn().unwrap();
+        state
+            .utxos
+            .set_main_block_height(&mut txn, main_block_height)
+            .unwrap();
+        txn.commit().unwrap();
+    }
+
+    /// Connects a main block, then calls `then`, and checks that disconnecting the main block
+    /// restores the state from before it was connected exactly. The main block is connected
+    /// again at the end.
+    fn check_main_block_disconnect(state: &State, main_block: &MainBlock, then: impl FnOnce()) {
+        let before = dump(state);
+        state.connect_main_block(main_block).unwrap();
+        then();
+        assert_ne!(dump(state), before);
+        state.disconnect_main_block(&main_block.block_hash).unwrap();
+        assert_eq!(dump(state), before);
+        state.connect_main_block(main_block).unwrap();
+    }
+
+    #[test]
+    fn disconnect_main_block_restores_connect() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let address = authorization::get_address(&signing_key.verifying_key());
+        let first_block = vec![sign_withdrawal(&signing_key, 0, vec![])];
+        let first_header = Header {
+            prev_side_block_hash: [0; HASH_LENGTH],
+            merkle_root: block::compute_merkle_root(&[], &first_block),
+        };
+        // Orphaned because the main block that BMMs it is disconnected.
+        let empty_header = Header {
+            prev_side_block_hash: first_header.hash(),
+            merkle_root: block::compute_merkle_root(&[], &[]),
+        };
+        // Orphaned because the main block with the deposit it spends is disconnected, although it
+        // was BMM'd before.
+        let deposit = OutPoint::Deposit { sequence_number: 4 };
+        let transaction = Transaction {
+            inputs: vec![deposit.clone()],
+            outputs: vec![Output::Regular {
+                address,
+                value: 40_000,
+            }],
+        };
+        let deposit_block = vec![authorize(&signing_key, transaction, vec![])];
+        let deposit_header = Header {
+            prev_side_block_hash: first_header.hash(),
+            merkle_root: block::compute_merkle_root(&[], &deposit_block),
+        };
+        let bmm_hashes = vec![first_header.hash(), deposit_header.hash()];
+        let (_dir, state) = new_state(&signing_key, bmm_hashes);
+        state
+            .connect(first_header.clone(), &[], &first_block)
+            .unwrap();
+        state.get_withdrawal_bundle().unwrap();
+        let m6id = state.preview_withdrawal_bundle().unwrap().m6id;
+
+        let new_deposit = |sequence_number| {
+            let outpoint = OutPoint::Deposit { sequence_number };
+            let output = Output::Regular {
+                address,
+                value: 50_000,
+            };
+            (outpoint, output)
+        };
+        // A known and a new BMM hash.
+        let main_block = next_main_block(
+            &state,
+            vec![new_deposit(3)],
+            Some((WithdrawalBundleEventType::Submitted, m6id)),
+            vec![first_header.hash(), empty_header.hash()],
+        );
+        check_main_block_disconnect(&state, &main_block, || {
+            state.connect(empty_header, &[], &[]).unwrap();
+        });
+        assert_eq!(state.get_chain_tip().unwrap().unwrap().0, 1);
+
+        let main_block = next_main_block(
+            &state,
+            vec![new_deposit(4)],
+            Some((WithdrawalBundleEventType::Failed, m6id)),
+            vec![deposit_header.hash()],
+        );
+        check_main_block_disconnect(&state, &main_block, || {
+            state.connect(deposit_header, &[], &deposit_block).unwrap();
+        });
+        assert_eq!(state.get_chain_tip().unwrap().unwrap().0, 1);
+
+        // The pending bundle expires.
+        skip_main_blocks(
+            &state,
+            main_block.block_height + BUNDLE_FAILURE_GRACE_WINDOW,
+        );
+        state.get_withdrawal_bundle().unwrap();
+        let preview = state.preview_withdrawal_bundle().unwrap();
+        assert!(preview.pending);
+        skip_main_blocks(
+            &state,
+            main_block.block_height + BUNDLE_FAILURE_GRACE_WINDOW + MAX_PENDING_BUNDLE_AGE,
+        );
+        let main_block = next_main_block(&state, vec![], None, vec![]);
+        check_main_block_disconnect(&state, &main_block, || {});
+        assert!(!state.preview_withdrawal_bundle().unwrap().pending);
+
+        skip_main_blocks(
+            &state,
+            main_block.block_height + BUNDLE_FAILURE_GRACE_WINDOW,
+        );
+        state.get_withdrawal_bundle().unwrap();
+        let m6id = state.preview_withdrawal_bundle().unwrap().m6id;
+        let main_block = next_main_block(
+            &state,
+            vec![],
+            Some((WithdrawalBundleEventType::Succeded, m6id)),
+            vec![],
+        );
+        check_main_block_disconnect(&state, &main_block, || {});
+        let withdrawal = OutPoint::Regular {
+            transaction_number: 0,
+            output_number: 0,
+        };
+        assert!(!state.get_utxo_set().unwrap().contains_key(&withdrawal));
+    }
+
+    #[test]
+    fn eviction_checks_authorizations_and_fees_again() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let (_dir, state) = new_state(&signing_key, vec![]);
+        let valid = sign(&signing_key, 0, 90_000);
+        // Entries that could only have been admitted against a different utxo set, before a
+        // reorg: one with another fee, and one authorized by a key that doesn't own its input.
+        let wrong_fee = sign(&signing_key, 1, 90_000);
+        let wrong_key = sign(&SigningKey::from_bytes(&[8; 32]), 2, 90_000);
+        let mut txn = state.env.write_txn().unwrap();
+        state.add_to_mempool(&mut txn, &valid).unwrap();
+        for (transaction, fee) in [(&wrong_fee, 20_000), (&wrong_key, 10_000)] {
+            let spent = SpentOutputs {
+                outpoints: transaction.transaction.inputs.clone(),
+                ..Default::default()
+            };
+            state
+                .mempool
+                .submit_transaction(&mut txn, transaction, fee, &spent)
+                .unwrap();
+        }
+        state.evict_invalid_transactions(&mut txn).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(mempool_hashes(&state), HashSet::from([valid.hash()]));
+    }
+
+    #[test]
+    fn header_must_commit_to_its_body() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let committed = vec![sign(&signing_key, 0, 90_000)];
+        let header = Header {
+            prev_side_block_hash: [0; HASH_LENGTH],
+            merkle_root: block::compute_merkle_root(&[], &committed),
+        };
+        let (_dir, state) = new_state(&signing_key, vec![header.hash()]);
+        // A valid body, but not the one the BMM'd header commits to.
+        let other = vec![sign(&signing_key, 1, 90_000)];
+        let error = state.connect(header.clone(), &[], &other).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(BlockError::WrongMerkleRoot { actual, .. }) if *actual == header.merkle_root
+        ));
+        assert!(state.get_chain_tip().unwrap().is_none());
+        state.connect(header, &[], &committed).unwrap();
+    }
+
+    #[test]
+    fn locked_withdrawals_cant_be_spent() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let address = authorization::get_address(&signing_key.verifying_key());
+        let first_block = vec![sign_withdrawal(&signing_key, 0, vec![])];
+        let first_header = Header {
+            prev_side_block_hash: [0; HASH_LENGTH],
+            merkle_root: block::compute_merkle_root(&[], &first_block),
+        };
+        let withdrawal = OutPoint::Regular {
+            transaction_number: 0,
+            output_number: 0,
+        };
+        let transaction = Transaction {
+            inputs: vec![withdrawal.clone()],
+            outputs: vec![Output::Regular {
+                address,
+                value: 90_000,
+            }],
+        };
+        let spend = authorize(&signing_key, transaction, vec![]);
+        let second_block = vec![spend.clone()];
+        let second_header = Header {
+            prev_side_block_hash: first_header.hash(),
+            merkle_root: block::compute_merkle_root(&[], &second_block),
+        };
+        let bmm_hashes = vec![first_header.hash(), second_header.hash()];
+        let (_dir, state) = new_state(&signing_key, bmm_hashes);
+        state.connect(first_header, &[], &first_block).unwrap();
+        state.get_withdrawal_bundle().unwrap();
+
+        let error = state.submit_transaction(&spend).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(TransactionError::LockedWithdrawal(outpoint)) if *outpoint == withdrawal
+        ));
+        let error = state.cancel_withdrawal(&spend).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(TransactionError::NotUnlockedWithdrawal(outpoint)) if *outpoint == withdrawal
+        ));
+        let error = state
+            .connect(second_header, &[], &second_block)
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(BlockError::InvalidTransaction {
+                index: 0,
+                source: TransactionError::LockedWithdrawal(outpoint),
+            }) if *outpoint == withdrawal
+        ));
+    }
+
+    #[test]
+    fn cancellations_only_spend_unlocked_withdrawals_to_regular_outputs() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let address = authorization::get_address(&signing_key.verifying_key());
+        let change = Output::Regular {
+            address,
+            value: 5_000,
+        };
+        let block = vec![sign_withdrawal(&signing_key, 0, vec![change])];
+        let header = Header {
+            prev_side_block_hash: [0; HASH_LENGTH],
+            merkle_root: block::compute_merkle_root(&[], &block),
+        };
+        let (_dir, state) = new_state(&signing_key, vec![header.hash()]);
+        state.connect(header, &[], &block).unwrap();
+        let withdrawal = OutPoint::Regular {
+            transaction_number: 0,
+            output_number: 0,
+        };
+        let regular = OutPoint::Regular {
+            transaction_number: 0,
+            output_number: 1,
+        };
+        let cancel = |input: &OutPoint, output: Output| {
+            let transaction = Transaction {
+                inputs: vec![input.clone()],
+                outputs: vec![output],
+            };
+            authorize(&signing_key, transaction, vec![])
+        };
+
+        let refund = Output::Regular {
+            address,
+            value: 90_000,
+        };
+        let error = state
+            .cancel_withdrawal(&cancel(&regular, refund.clone()))
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(TransactionError::NotUnlockedWithdrawal(outpoint)) if *outpoint == regular
+        ));
+        let rewithdrawal = Output::Withdrawal {
+            address,
+            main_address: [4; 20],
+            value: 80_000,
+            fee: 1_000,
+        };
+        let error = state
+            .cancel_withdrawal(&cancel(&withdrawal, rewithdrawal))
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(TransactionError::NonRegularCancellationOutput(0))
+        ));
+        let cancellation = cancel(&withdrawal, refund);
+        state.cancel_withdrawal(&cancellation).unwrap();
+        assert_eq!(mempool_hashes(&state), HashSet::from([cancellation.hash()]));
+    }
+
+    #[test]
+    fn values_that_overflow_are_rejected() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let address = authorization::get_address(&signing_key.verifying_key());
+        // Sums to 0 if it wraps around.
+        let outputs = vec![
+            Output::Regular {
+                address,
+                value: u64::MAX,
+            },
+            Output::Regular { address, value: 1 },
+        ];
+        let header = Header {
+            prev_side_block_hash: [0; HASH_LENGTH],
+            merkle_root: block::compute_merkle_root(&outputs, &[]),
+        };
+        let (_dir, state) = new_state(&signing_key, vec![header.hash()]);
+        let error = state.connect(header, &outputs, &[]).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(BlockError::ValueOverflow)
+        ));
+        let transaction = Transaction {
+            inputs: vec![OutPoint::Deposit { sequence_number: 0 }],
+            outputs,
+        };
+        let transaction = authorize(&signing_key, transaction, vec![]);
+        let error = state.submit_transaction(&transaction).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(TransactionError::ValueOverflow)
+        ));
+    }
+
+    #[test]
+    fn unconfirmed_chains_are_mined_and_disconnected_together() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let parent = sign(&signing_key, 0, 99_999);
+        let child = sign_child(&signing_key, &parent, 80_000);
+        let unrelated = sign(&signing_key, 1, 95_000);
+        // The child pays for its parent, so their package has a higher fee rate than the
+        // unrelated transaction, although the parent alone has a lower one.
+        let transactions = vec![parent.clone(), child.clone(), unrelated.clone()];
+        let header = Header {
+            prev_side_block_hash: [0; HASH_LENGTH],
+            merkle_root: block::compute_merkle_root(&[], &transactions),
+        };
+        let (_dir, state) = new_state(&signing_key, vec![header.hash()]);
+        state.submit_transaction(&parent).unwrap();
+        state.submit_transaction(&child).unwrap();
+        state.submit_transaction(&unrelated).unwrap();
+        let collected: Vec<_> = state
+            .collect_transactions()
+            .unwrap()
+            .iter()
+            .map(|transaction| transaction.hash())
+            .collect();
+        let hashes: Vec<_> = transactions
+            .iter()
+            .map(|transaction| transaction.hash())
+            .collect();
+        assert_eq!(collected, hashes);
+        state.connect(header, &[], &transactions).unwrap();
+        assert!(mempool_hashes(&state).is_empty());
+        let utxo_set = state.get_utxo_set().unwrap();
+        let parent_output = OutPoint::Regular {
+            transaction_number: 0,
+            output_number: 0,
+        };
+        let child_output = OutPoint::Regular {
+            transaction_number: 1,
+            output_number: 0,
+        };
+        assert!(!utxo_set.contains_key(&parent_output));
+        assert_eq!(utxo_set[&child_output].total_value(), 80_000);
+        state.disconnect(1).unwrap();
+        assert_eq!(mempool_hashes(&state), HashSet::from_iter(hashes));
+        assert_eq!(state.get_utxo_set().unwrap().len(), 3);
+    }
 }
@@ -0,0 +1,80 @@
+use cusf_sidechain_types::{OutPoint, Output, HASH_LENGTH};
+use serde::{Deserialize, Serialize};
+
+use crate::withdrawal::WithdrawalDestination;
+
+/// Number of 16 bit lanes, like in LtHash16.
+const LANES: usize = 1024;
+
+/// Multiset hash of the utxo set, in the style of LtHash.
+///
+/// Every utxo is expanded with the blake3 XOF into LANES 16 bit integers, which are added to the
+/// lanes of the hash when the utxo is created, and subtracted when it is spent. Addition is
+/// commutative, so the hash only depends on the utxos in the set and not on the order in which
+/// they were added, and it can be updated without rehashing the whole set.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UtxoSetHash {
+    lanes: Vec<u16>,
+}
+
+impl Default for UtxoSetHash {
+    fn default() -> Self {
+        Self {
+            lanes: vec![0; LANES],
+        }
+    }
+}
+
+impl UtxoSetHash {
+    /// Withdrawals are hashed together with their destination, if they have one, since it decides
+    /// where they are paid out.
+    pub fn insert(
+        &mut self,
+        outpoint: &OutPoint,
+        output: &Output,
+        destination: Option<&WithdrawalDestination>,
+    ) {
+        let element = expand(outpoint, output, destination);
+        for (lane, element) in self.lanes.iter_mut().zip(element) {
+            *lane = lane.wrapping_add(element);
+        }
+    }
+
+    pub fn remove(
+        &mut self,
+        outpoint: &OutPoint,
+        output: &Output,
+        destination: Option<&WithdrawalDestination>,
+    ) {
+        let element = expand(outpoint, output, destination);
+        for (lane, element) in self.lanes.iter_mut().zip(element) {
+            *lane = lane.wrapping_sub(element);
+        }
+    }
+
+    /// Short commitment to the utxo set, for comparing it across nodes.
+    pub fn commitment(&self) -> [u8; HASH_LENGTH] {
+        let mut hasher = blake3::Hasher::new();
+        for lane in &self.lanes {
+            hasher.update(&lane.to_le_bytes());
+        }
+        hasher.finalize().into()
+    }
+}
+
+fn expand(
+    outpoint: &OutPoint,
+    output: &Output,
+    destination: Option<&WithdrawalDestination>,
+) -> Vec<u16> {
+    let bytes =
+        bincode::serialize(&(outpoint, output, destination)).expect("failed to serialize utxo");
+    let mut xof = vec![0; LANES * 2];
+    blake3::Hasher::new()
+        .update(&bytes)
+        .finalize_xof()
+        .fill(&mut xof);
+    xof.chunks_exact(2)
+        .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+        .collect()
+}
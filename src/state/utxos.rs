@@ -1,12 +1,52 @@
-use bitcoin::opcodes::all::{OP_CHECKSIG, OP_DUP, OP_EQUALVERIFY, OP_HASH160, OP_RETURN};
-use bitcoin::TxOut;
-use cusf_sidechain_types::{OutPoint, Output, Transaction, ADDRESS_LENGTH, HASH_LENGTH};
+use bitcoin::constants::WITNESS_SCALE_FACTOR;
+use bitcoin::hashes::Hash as _;
+use bitcoin::opcodes::all::OP_RETURN;
+use bitcoin::{ScriptBuf, TxOut, VarInt};
+use cusf_sidechain_types::{OutPoint, Output, ADDRESS_LENGTH, HASH_LENGTH};
 use heed::{types::*, Env};
 use heed::{Database, RoTxn, RwTxn};
 use miette::{miette, IntoDiagnostic, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 
+use super::codec::BigEndian;
+use super::error::{BlockError, TransactionError};
+use super::utxo_set_hash::UtxoSetHash;
+use crate::authorization::AuthorizedTransaction;
+use crate::withdrawal::WithdrawalDestination;
+
+/// Output numbers are encoded as u8, so neither a coinbase nor a transaction can have more outputs.
+pub const MAX_OUTPUTS_LEN: usize = 256;
+
+/// Number of main blocks to wait after a bundle failed before collecting a new one.
+pub const BUNDLE_FAILURE_GRACE_WINDOW: u32 = 24 * 6; // 1 Day
+/// Number of main blocks after collection, after which a pending bundle is considered failed.
+pub const MAX_PENDING_BUNDLE_AGE: u32 = 10 * 24 * 6; // 10 Days
+
+/// Maximum number of outputs in a withdrawal bundle, not counting the fee output.
+const MAX_WITHDRAWAL_BUNDLE_OUTPUTS: usize = 6000;
+/// Weight reserved for the treasury input and output, which the enforcer adds to the bundle.
+const RESERVED_BUNDLE_WEIGHT: u64 = 1_000;
+/// Maximum weight of a withdrawal bundle, so that mainchain nodes still relay it as standard once
+/// the enforcer has added the treasury input and output.
+const MAX_WITHDRAWAL_BUNDLE_WEIGHT: u64 =
+    bitcoin::policy::MAX_STANDARD_TX_WEIGHT as u64 - RESERVED_BUNDLE_WEIGHT;
+
+/// Total value of outputs, or None if it doesn't fit in a u64.
+///
+/// `Output::total_value` and `Transaction::value_out` don't check for overflow, so values that
+/// come from blocks or transactions are summed with this instead. Otherwise a block could pay out
+/// more than it spends, by making a sum wrap around.
+pub fn checked_value_sum<'a>(outputs: impl IntoIterator<Item = &'a Output>) -> Option<u64> {
+    outputs.into_iter().try_fold(0u64, |sum, output| {
+        let value = match output {
+            Output::Regular { value, .. } => *value,
+            Output::Withdrawal { value, fee, .. } => value.checked_add(*fee)?,
+        };
+        sum.checked_add(value)
+    })
+}
+
 /// Unit key. LMDB can't use zero-sized keys, so this encodes to a single byte
 #[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
 pub struct UnitKey;
@@ -32,9 +72,116 @@ impl Serialize for UnitKey {
     }
 }
 
+/// Everything [`Utxos::connect`] changed for a single block, so that [`Utxos::disconnect`] can
+/// restore the previous state exactly.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BlockUndo {
+    /// Spent outpoints with the outputs they pointed to, in the order they were spent.
+    spent: Vec<(OutPoint, Output)>,
+    /// Outpoints created by the block, including coinbase outputs.
+    created: Vec<OutPoint>,
+    /// Spent outpoints that were removed from unlocked withdrawals.
+    spent_unlocked_withdrawals: Vec<OutPoint>,
+    /// Last transaction number before the block was connected.
+    prev_transaction_number: Option<u64>,
+    /// Side block height before the block was connected.
+    prev_side_block_height: Option<u32>,
+}
+
+/// Everything [`crate::state::State::connect_main_block`] changed for a single main block, so
+/// that the main block can be disconnected during a main chain reorg.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MainBlockUndo {
+    pub prev_main_block_height: u32,
+    pub prev_main_chain_tip: [u8; HASH_LENGTH],
+    /// Side block height at the time the main block was connected.
+    pub side_block_height: u32,
+    /// Deposit outpoints added by the main block.
+    pub deposits: Vec<OutPoint>,
+    /// BMM hashes added by the main block, that weren't already known.
+    pub bmm_hashes: Vec<[u8; HASH_LENGTH]>,
+    pub bundle_event: Option<BundleEventUndo>,
+    /// Pending bundle that was failed because it got too old.
+    pub expired_bundle: Option<BundleEventUndo>,
+}
+
+/// Changes made by a withdrawal bundle event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BundleEventUndo {
+    /// The event wasn't about our bundle, so nothing was changed.
+    Ignored,
+    Submitted {
+        m6id: [u8; HASH_LENGTH],
+    },
+    Succeeded {
+        m6id: [u8; HASH_LENGTH],
+        bundle: PendingBundle,
+        /// Withdrawal outputs that were paid out, and removed from the utxo set.
+        spent: Vec<Output>,
+    },
+    Failed {
+        m6id: [u8; HASH_LENGTH],
+        bundle: PendingBundle,
+        prev_bundle_failure_main_height: Option<u32>,
+    },
+}
+
+/// Withdrawal bundle that was collected, but neither succeeded nor failed yet.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingBundle {
+    /// Locked withdrawal outpoints, in the order they are paid out by the bundle.
+    pub outpoints: Vec<OutPoint>,
+    pub collection_main_height: u32,
+    pub submitted: bool,
+}
+
+/// Next withdrawal bundle, as it would be handed to the enforcer.
+#[derive(Clone, Debug)]
+pub struct BundlePreview {
+    pub m6id: [u8; HASH_LENGTH],
+    pub bundle: bitcoin::Transaction,
+    /// Withdrawal outpoints, in the order they are paid out by the bundle.
+    pub outpoints: Vec<OutPoint>,
+    pub total_fee: u64,
+    pub weight: u64,
+    /// True if the bundle was already collected and its withdrawals are locked, false if the
+    /// withdrawals would be selected from the currently unlocked ones.
+    pub pending: bool,
+}
+
+/// Stage of a withdrawal, from its creation on the sidechain to its payout on the mainchain.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum WithdrawalState {
+    /// Waiting to be collected into a bundle.
+    Unlocked,
+    /// Collected into a pending bundle.
+    Locked,
+    /// Bundle was submitted to the mainchain.
+    Submitted,
+    /// Bundle succeeded, and the withdrawal was paid out on the mainchain.
+    PaidOut,
+    /// Bundle failed or expired, and the withdrawal is unlocked again.
+    Returned,
+    /// Withdrawal was spent on the sidechain before it was locked.
+    Cancelled,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WithdrawalTransition {
+    pub state: WithdrawalState,
+    /// Bundle that caused the transition, if any.
+    pub m6id: Option<[u8; HASH_LENGTH]>,
+    /// Main block height at the time of the transition.
+    pub main_block_height: u32,
+}
+
 #[derive(Clone)]
 pub struct Utxos {
     utxos: Database<SerdeBincode<OutPoint>, SerdeBincode<Output>>,
+    /// Hash of the current utxo set, kept up to date by every change to `utxos`.
+    utxo_set_hash: Database<SerdeBincode<UnitKey>, SerdeBincode<UtxoSetHash>>,
+    /// Side block height -> Commitment to the utxo set right after the block was connected
+    utxo_set_commitments: Database<BigEndian<u32>, SerdeBincode<[u8; HASH_LENGTH]>>,
     transaction_number: Database<SerdeBincode<UnitKey>, SerdeBincode<u64>>,
     main_block_height: Database<SerdeBincode<UnitKey>, SerdeBincode<u32>>,
     main_chain_tip: Database<SerdeBincode<UnitKey>, SerdeBincode<[u8; HASH_LENGTH]>>,
@@ -43,14 +190,35 @@ pub struct Utxos {
     // At what point are withdrawals locked?
     // When a mainchain block with M3 is first mined.
     locked_withdrawals: Database<SerdeBincode<OutPoint>, Unit>,
-    bundle_collection_main_height: Database<SerdeBincode<UnitKey>, SerdeBincode<u32>>,
+    /// M6ID -> Pending bundle
+    pending_bundles: Database<SerdeBincode<[u8; HASH_LENGTH]>, SerdeBincode<PendingBundle>>,
+    /// Withdrawal outpoint -> Transitions, oldest first
+    ///
+    /// Entries are kept after the withdrawal is spent or paid out, so users can still look them up.
+    withdrawal_statuses: Database<SerdeBincode<OutPoint>, SerdeBincode<Vec<WithdrawalTransition>>>,
+    /// Withdrawal outpoint -> Destination, for withdrawals whose transaction specified one
+    ///
+    /// Destinations are part of the utxo set hash. Entries are kept like withdrawal statuses, so
+    /// withdrawals that are restored to the utxo set get their destination back.
+    withdrawal_destinations: Database<SerdeBincode<OutPoint>, SerdeBincode<WithdrawalDestination>>,
+    bundle_failure_main_height: Database<SerdeBincode<UnitKey>, SerdeBincode<u32>>,
+    /// Side block height -> Undo data
+    block_undos: Database<BigEndian<u32>, SerdeBincode<BlockUndo>>,
+    /// Main block hash -> Undo data
+    main_block_undos: Database<SerdeBincode<[u8; HASH_LENGTH]>, SerdeBincode<MainBlockUndo>>,
 }
 
 impl Utxos {
-    pub const NUM_DBS: u32 = 8;
+    pub const NUM_DBS: u32 = 15;
 
     pub fn new(env: &Env) -> Result<Self> {
         let utxos = env.create_database(Some("utxos")).into_diagnostic()?;
+        let utxo_set_hash = env
+            .create_database(Some("utxos_utxo_set_hash"))
+            .into_diagnostic()?;
+        let utxo_set_commitments = env
+            .create_database(Some("utxos_utxo_set_commitments"))
+            .into_diagnostic()?;
         let transaction_number = env
             .create_database(Some("transaction_number"))
             .into_diagnostic()?;
@@ -69,18 +237,40 @@ impl Utxos {
         let locked_withdrawals = env
             .create_database(Some("utxos_locked_withdrawals"))
             .into_diagnostic()?;
-        let bundle_collection_main_height = env
-            .create_database(Some("bundle_collection_main_height"))
+        let pending_bundles = env
+            .create_database(Some("utxos_pending_bundles"))
+            .into_diagnostic()?;
+        let withdrawal_statuses = env
+            .create_database(Some("utxos_withdrawal_statuses"))
+            .into_diagnostic()?;
+        let withdrawal_destinations = env
+            .create_database(Some("utxos_withdrawal_destinations"))
+            .into_diagnostic()?;
+        let bundle_failure_main_height = env
+            .create_database(Some("bundle_failure_main_height"))
+            .into_diagnostic()?;
+        let block_undos = env
+            .create_database(Some("utxos_block_undos"))
+            .into_diagnostic()?;
+        let main_block_undos = env
+            .create_database(Some("utxos_main_block_undos"))
             .into_diagnostic()?;
         Ok(Self {
             utxos,
+            utxo_set_hash,
+            utxo_set_commitments,
             transaction_number,
             main_block_height,
             main_chain_tip,
             side_block_height,
             unlocked_withdrawals,
             locked_withdrawals,
-            bundle_collection_main_height,
+            pending_bundles,
+            withdrawal_statuses,
+            withdrawal_destinations,
+            bundle_failure_main_height,
+            block_undos,
+            main_block_undos,
         })
     }
 
@@ -130,6 +320,37 @@ impl Utxos {
         Ok(())
     }
 
+    pub fn put_main_block_undo(
+        &self,
+        txn: &mut RwTxn,
+        block_hash: &[u8; HASH_LENGTH],
+        undo: &MainBlockUndo,
+    ) -> Result<()> {
+        self.main_block_undos
+            .put(txn, block_hash, undo)
+            .into_diagnostic()?;
+        Ok(())
+    }
+
+    pub fn take_main_block_undo(
+        &self,
+        txn: &mut RwTxn,
+        block_hash: &[u8; HASH_LENGTH],
+    ) -> Result<MainBlockUndo> {
+        let undo = self
+            .main_block_undos
+            .get(txn, block_hash)
+            .into_diagnostic()?
+            .ok_or(miette!(
+                "no undo data for main block {}",
+                hex::encode(block_hash)
+            ))?;
+        self.main_block_undos
+            .delete(txn, block_hash)
+            .into_diagnostic()?;
+        Ok(undo)
+    }
+
     pub fn get_side_block_height(&self, txn: &RoTxn) -> Result<u32> {
         let height = self
             .side_block_height
@@ -139,92 +360,290 @@ impl Utxos {
         Ok(height)
     }
 
+    /// Number that [`Utxos::connect`] gives to the first transaction of the next block.
+    pub fn get_next_transaction_number(&self, txn: &RoTxn) -> Result<u64> {
+        let transaction_number = self
+            .transaction_number
+            .get(txn, &UnitKey)
+            .into_diagnostic()?
+            .map_or(0, |transaction_number| transaction_number + 1);
+        Ok(transaction_number)
+    }
+
     pub fn is_empty(&self, txn: &RoTxn) -> Result<bool> {
         self.utxos.is_empty(txn).into_diagnostic()
     }
 
-    pub fn add_utxo(&self, txn: &mut RwTxn, outpoint: &OutPoint, output: &Output) -> Result<()> {
+    pub fn add_utxos(&self, txn: &mut RwTxn, utxos: &[(OutPoint, Output)]) -> Result<()> {
+        let mut utxo_set_hash = self.get_utxo_set_hash(txn)?;
+        for (outpoint, output) in utxos {
+            self.insert_utxo(txn, &mut utxo_set_hash, outpoint, output)?;
+        }
+        self.put_utxo_set_hash(txn, &utxo_set_hash)
+    }
+
+    /// Outpoints that aren't in the utxo set are skipped.
+    pub fn remove_utxos(&self, txn: &mut RwTxn, outpoints: &[OutPoint]) -> Result<()> {
+        let mut utxo_set_hash = self.get_utxo_set_hash(txn)?;
+        for outpoint in outpoints {
+            self.delete_utxo(txn, &mut utxo_set_hash, outpoint)?;
+        }
+        self.put_utxo_set_hash(txn, &utxo_set_hash)
+    }
+
+    /// Adds a utxo, and updates the utxo set hash in memory.
+    ///
+    /// The hash is 2 KiB, so changes that touch many utxos load it once and write it back once
+    /// with [`Utxos::put_utxo_set_hash`], instead of for every utxo. The destination of a
+    /// withdrawal must be stored before the withdrawal is added, so it is hashed with it.
+    fn insert_utxo(
+        &self,
+        txn: &mut RwTxn,
+        utxo_set_hash: &mut UtxoSetHash,
+        outpoint: &OutPoint,
+        output: &Output,
+    ) -> Result<()> {
+        // Overwriting would leave the output it replaces in the hash.
+        if self.utxos.get(txn, outpoint).into_diagnostic()?.is_some() {
+            return Err(miette!("outpoint {outpoint} is already in the utxo set"));
+        }
         self.utxos.put(txn, outpoint, output).into_diagnostic()?;
+        let destination = self.get_withdrawal_destination(txn, outpoint, output)?;
+        utxo_set_hash.insert(outpoint, output, destination.as_ref());
         Ok(())
     }
 
-    pub fn remove_utxo(&self, txn: &mut RwTxn, outpoint: &OutPoint) -> Result<()> {
+    /// Removes a utxo like [`Utxos::insert_utxo`] adds one.
+    ///
+    /// Returns the removed output, or None if the outpoint wasn't in the utxo set.
+    fn delete_utxo(
+        &self,
+        txn: &mut RwTxn,
+        utxo_set_hash: &mut UtxoSetHash,
+        outpoint: &OutPoint,
+    ) -> Result<Option<Output>> {
+        let Some(output) = self.utxos.get(txn, outpoint).into_diagnostic()? else {
+            return Ok(None);
+        };
         self.utxos.delete(txn, outpoint).into_diagnostic()?;
+        let destination = self.get_withdrawal_destination(txn, outpoint, &output)?;
+        utxo_set_hash.remove(outpoint, &output, destination.as_ref());
+        Ok(Some(output))
+    }
+
+    /// Destination of a withdrawal, or None for withdrawals without one and for regular outputs.
+    fn get_withdrawal_destination(
+        &self,
+        txn: &RoTxn,
+        outpoint: &OutPoint,
+        output: &Output,
+    ) -> Result<Option<WithdrawalDestination>> {
+        if !matches!(output, Output::Withdrawal { .. }) {
+            return Ok(None);
+        }
+        let destination = self
+            .withdrawal_destinations
+            .get(txn, outpoint)
+            .into_diagnostic()?;
+        Ok(destination)
+    }
+
+    fn get_utxo_set_hash(&self, txn: &RoTxn) -> Result<UtxoSetHash> {
+        let utxo_set_hash = self
+            .utxo_set_hash
+            .get(txn, &UnitKey)
+            .into_diagnostic()?
+            .unwrap_or_default();
+        Ok(utxo_set_hash)
+    }
+
+    fn put_utxo_set_hash(&self, txn: &mut RwTxn, utxo_set_hash: &UtxoSetHash) -> Result<()> {
+        self.utxo_set_hash
+            .put(txn, &UnitKey, utxo_set_hash)
+            .into_diagnostic()?;
         Ok(())
     }
 
-    // FIXME: Maybe return an error instead of Ok(false)?
+    /// Recomputes the utxo set hash from the whole utxo set, for utxo sets that were built before
+    /// the hash existed.
+    ///
+    /// Commitments of blocks connected before then can't be recovered, and stay missing.
+    pub fn rebuild_utxo_set_hash(&self, txn: &mut RwTxn) -> Result<()> {
+        let mut utxo_set_hash = UtxoSetHash::default();
+        for item in self.utxos.iter(txn).into_diagnostic()? {
+            let (outpoint, output) = item.into_diagnostic()?;
+            let destination = self.get_withdrawal_destination(txn, &outpoint, &output)?;
+            utxo_set_hash.insert(&outpoint, &output, destination.as_ref());
+        }
+        self.put_utxo_set_hash(txn, &utxo_set_hash)
+    }
+
+    /// Returns the commitment to the utxo set as it was right after the side block at
+    /// `block_height` was connected.
+    pub fn get_utxo_set_commitment(
+        &self,
+        txn: &RoTxn,
+        block_height: u32,
+    ) -> Result<Option<[u8; HASH_LENGTH]>> {
+        let commitment = self
+            .utxo_set_commitments
+            .get(txn, &block_height)
+            .into_diagnostic()?;
+        Ok(commitment)
+    }
+
     pub fn validate(
         &self,
         txn: &RoTxn,
         coinbase: &[Output],
-        transactions: &[Transaction],
-    ) -> Result<bool> {
+        transactions: &[AuthorizedTransaction],
+    ) -> Result<()> {
+        if coinbase.len() > MAX_OUTPUTS_LEN {
+            return Err(BlockError::TooManyCoinbaseOutputs {
+                len: coinbase.len(),
+                limit: MAX_OUTPUTS_LEN,
+            }
+            .into());
+        }
         let mut spent_utxos = HashSet::new();
-        let mut total_fees = 0;
-        for transaction in transactions {
-            let mut value_in = 0;
+        // Outputs of earlier transactions in the block, which later ones can spend.
+        let mut created: HashMap<OutPoint, &Output> = HashMap::new();
+        let mut transaction_number = self.get_next_transaction_number(txn)?;
+        let mut total_fees: u64 = 0;
+        for (index, transaction) in transactions.iter().enumerate() {
+            let invalid = |source| BlockError::InvalidTransaction { index, source };
+            let transaction = &transaction.transaction;
+            if transaction.outputs.len() > MAX_OUTPUTS_LEN {
+                return Err(invalid(TransactionError::TooManyOutputs {
+                    len: transaction.outputs.len(),
+                    limit: MAX_OUTPUTS_LEN,
+                })
+                .into());
+            }
+            let mut spent_outputs = vec![];
             for input in &transaction.inputs {
                 if spent_utxos.contains(input) {
-                    return Ok(false);
+                    return Err(invalid(TransactionError::DoubleSpend(input.clone())).into());
                 }
-                let spent_utxo = self.utxos.get(txn, input).into_diagnostic()?;
-                let value = match spent_utxo {
-                    Some(spent_utxo) => spent_utxo.total_value(),
+                let spent_utxo = match created.get(input) {
+                    Some(output) => Some((*output).clone()),
                     None => {
-                        return Ok(false);
+                        if self.is_locked_withdrawal(txn, input)? {
+                            return Err(
+                                invalid(TransactionError::LockedWithdrawal(input.clone())).into()
+                            );
+                        }
+                        self.utxos.get(txn, input).into_diagnostic()?
                     }
                 };
-                value_in += value;
+                let Some(spent_utxo) = spent_utxo else {
+                    return Err(invalid(TransactionError::MissingInput(input.clone())).into());
+                };
+                spent_outputs.push(spent_utxo);
                 spent_utxos.insert(input);
             }
-            let value_out = transaction.value_out();
+            let value_in = checked_value_sum(&spent_outputs)
+                .ok_or(invalid(TransactionError::ValueOverflow))?;
+            let value_out = checked_value_sum(&transaction.outputs)
+                .ok_or(invalid(TransactionError::ValueOverflow))?;
             if value_out > value_in {
-                return Ok(false);
+                return Err(invalid(TransactionError::Overspend {
+                    value_in,
+                    value_out,
+                })
+                .into());
             }
             let fee = value_in - value_out;
-            total_fees += fee;
+            total_fees = total_fees
+                .checked_add(fee)
+                .ok_or(BlockError::ValueOverflow)?;
+            for (output_number, output) in transaction.outputs.iter().enumerate() {
+                let outpoint = OutPoint::Regular {
+                    transaction_number,
+                    output_number: output_number as u8,
+                };
+                created.insert(outpoint, output);
+            }
+            transaction_number += 1;
         }
-        let coinbase_value: u64 = coinbase.iter().map(|output| output.total_value()).sum();
+        let coinbase_value = checked_value_sum(coinbase).ok_or(BlockError::ValueOverflow)?;
         if coinbase_value > total_fees {
-            return Ok(false);
+            return Err(BlockError::CoinbaseOverpay {
+                coinbase_value,
+                total_fees,
+            }
+            .into());
         }
-        Ok(true)
+        Ok(())
     }
 
     /// Performs no validation, assumes that all transactions are valid.
+    ///
+    /// Records a [`BlockUndo`] for the block, so it can be reverted with [`Utxos::disconnect`].
     pub fn connect(
         &self,
         txn: &mut RwTxn,
         block_height: u32,
         coinbase: &[Output],
-        transactions: &[Transaction],
+        transactions: &[AuthorizedTransaction],
     ) -> Result<()> {
-        const MAX_OUTPUTS_LEN: usize = 256;
         if coinbase.len() > MAX_OUTPUTS_LEN {
             return Err(miette!("too many outputs in coinbase"));
         }
+        let prev_transaction_number = self
+            .transaction_number
+            .get(txn, &UnitKey)
+            .into_diagnostic()?;
+        let prev_side_block_height = self
+            .side_block_height
+            .get(txn, &UnitKey)
+            .into_diagnostic()?;
+        let mut undo = BlockUndo {
+            prev_transaction_number,
+            prev_side_block_height,
+            ..Default::default()
+        };
+        let mut utxo_set_hash = self.get_utxo_set_hash(txn)?;
         for (output_number, output) in coinbase.iter().enumerate() {
             let outpoint = OutPoint::Coinbase {
                 block_number: block_height,
                 output_number: output_number as u8,
             };
-            self.utxos.put(txn, &outpoint, &output).into_diagnostic()?;
+            self.insert_utxo(txn, &mut utxo_set_hash, &outpoint, output)?;
+            undo.created.push(outpoint);
         }
-        let transaction_number = self
-            .transaction_number
-            .get(txn, &UnitKey)
-            .into_diagnostic()?;
-        let mut transaction_number = match transaction_number {
+        let main_block_height = self.get_main_block_height(txn)?;
+        let mut transaction_number = match prev_transaction_number {
             Some(transaction_number) => transaction_number + 1,
             None => 0,
         };
         for transaction in transactions {
+            let mut withdrawal_destinations = transaction.withdrawal_destinations.iter();
+            let transaction = &transaction.transaction;
             for input in &transaction.inputs {
-                self.utxos.delete(txn, &input).into_diagnostic()?;
-                self.unlocked_withdrawals
+                if self.is_locked_withdrawal(txn, input)? {
+                    return Err(miette!("input {input} is a locked withdrawal"));
+                }
+                let spent_output = self
+                    .delete_utxo(txn, &mut utxo_set_hash, input)?
+                    .ok_or(miette!("input {input} doesn't exist"))?;
+                // Spending a withdrawal before it is locked in a bundle cancels it, and its value
+                // goes to the outputs of the spending transaction.
+                if self
+                    .unlocked_withdrawals
                     .delete(txn, &input)
-                    .into_diagnostic()?;
+                    .into_diagnostic()?
+                {
+                    self.push_withdrawal_transition(
+                        txn,
+                        input,
+                        WithdrawalState::Cancelled,
+                        None,
+                        main_block_height,
+                    )?;
+                    undo.spent_unlocked_withdrawals.push(input.clone());
+                }
+                undo.spent.push((input.clone(), spent_output));
             }
             if transaction.outputs.len() > MAX_OUTPUTS_LEN {
                 return Err(miette!("too many outputs in transaction"));
@@ -234,23 +653,35 @@ impl Utxos {
                     transaction_number,
                     output_number: output_number as u8,
                 };
-                self.utxos.put(txn, &outpoint, &output).into_diagnostic()?;
-                if matches!(output, Output::Withdrawal { .. }) {
+                let is_withdrawal = matches!(output, Output::Withdrawal { .. });
+                if is_withdrawal {
+                    if let Some(destination) = withdrawal_destinations.next() {
+                        self.withdrawal_destinations
+                            .put(txn, &outpoint, destination)
+                            .into_diagnostic()?;
+                    }
+                }
+                self.insert_utxo(txn, &mut utxo_set_hash, &outpoint, output)?;
+                if is_withdrawal {
                     self.unlocked_withdrawals
                         .put(txn, &outpoint, &())
                         .into_diagnostic()?;
+                    self.push_withdrawal_transition(
+                        txn,
+                        &outpoint,
+                        WithdrawalState::Unlocked,
+                        None,
+                        main_block_height,
+                    )?;
                 }
+                undo.created.push(outpoint);
             }
             self.transaction_number
                 .put(txn, &UnitKey, &transaction_number)
                 .into_diagnostic()?;
             transaction_number += 1;
         }
-        let side_block_height = self
-            .side_block_height
-            .get(txn, &UnitKey)
-            .into_diagnostic()?;
-        let side_block_height = match side_block_height {
+        let side_block_height = match prev_side_block_height {
             Some(side_block_height) => side_block_height + 1,
             // 0th block is Genesis.
             None => 0 + 1,
@@ -258,29 +689,154 @@ impl Utxos {
         self.side_block_height
             .put(txn, &UnitKey, &side_block_height)
             .into_diagnostic()?;
+        self.block_undos
+            .put(txn, &side_block_height, &undo)
+            .into_diagnostic()?;
+        self.put_utxo_set_hash(txn, &utxo_set_hash)?;
+        let commitment = utxo_set_hash.commitment();
+        self.utxo_set_commitments
+            .put(txn, &side_block_height, &commitment)
+            .into_diagnostic()?;
         Ok(())
     }
 
-    /// Performs no validation, assumes that all transactions are valid.
-    pub fn disconnect(
-        &self,
-        txn: &mut RwTxn,
-        block_height: u32,
-        coinbase: &[Output],
-        transactions: &[Transaction],
-    ) -> Result<()> {
-        todo!();
+    /// Reverts [`Utxos::connect`] for the latest block, using the recorded [`BlockUndo`].
+    pub fn disconnect(&self, txn: &mut RwTxn, block_height: u32) -> Result<()> {
+        let side_block_height = self.get_side_block_height(txn)?;
+        if block_height != side_block_height {
+            return Err(miette!(
+                "can't disconnect block {block_height}, side chain tip is {side_block_height}"
+            ));
+        }
+        let undo = self
+            .block_undos
+            .get(txn, &block_height)
+            .into_diagnostic()?
+            .ok_or(miette!("no undo data for block {block_height}"))?;
+        // Withdrawals created by the block may have been locked into a pending bundle since, which
+        // can't be paid out without them.
+        for outpoint in &undo.created {
+            if self.is_locked_withdrawal(txn, outpoint)? {
+                self.drop_bundle(txn, outpoint)?;
+            }
+        }
+        // Spent outputs are restored first, since the block may have spent outputs it created
+        // itself. Outputs created by the block can only be spent by the block itself or by later
+        // blocks, which were already disconnected, so then all of them are in the utxo set.
+        let mut utxo_set_hash = self.get_utxo_set_hash(txn)?;
+        for (outpoint, output) in &undo.spent {
+            self.insert_utxo(txn, &mut utxo_set_hash, outpoint, output)?;
+        }
+        for outpoint in &undo.spent_unlocked_withdrawals {
+            self.unlocked_withdrawals
+                .put(txn, outpoint, &())
+                .into_diagnostic()?;
+            self.pop_withdrawal_transition(txn, outpoint)?;
+        }
+        for outpoint in &undo.created {
+            self.delete_utxo(txn, &mut utxo_set_hash, outpoint)?;
+            self.unlocked_withdrawals
+                .delete(txn, outpoint)
+                .into_diagnostic()?;
+            self.withdrawal_statuses
+                .delete(txn, outpoint)
+                .into_diagnostic()?;
+            self.withdrawal_destinations
+                .delete(txn, outpoint)
+                .into_diagnostic()?;
+        }
+        self.put_utxo_set_hash(txn, &utxo_set_hash)?;
+        match undo.prev_transaction_number {
+            Some(transaction_number) => {
+                self.transaction_number
+                    .put(txn, &UnitKey, &transaction_number)
+                    .into_diagnostic()?;
+            }
+            None => {
+                self.transaction_number
+                    .delete(txn, &UnitKey)
+                    .into_diagnostic()?;
+            }
+        }
+        match undo.prev_side_block_height {
+            Some(side_block_height) => {
+                self.side_block_height
+                    .put(txn, &UnitKey, &side_block_height)
+                    .into_diagnostic()?;
+            }
+            None => {
+                self.side_block_height
+                    .delete(txn, &UnitKey)
+                    .into_diagnostic()?;
+            }
+        }
+        self.block_undos
+            .delete(txn, &block_height)
+            .into_diagnostic()?;
+        self.utxo_set_commitments
+            .delete(txn, &block_height)
+            .into_diagnostic()?;
+        Ok(())
     }
 
+    /// Returns the pending withdrawal bundle, or a bundle without withdrawals if there is none.
     pub fn get_withdrawal_bundle(&self, txn: &RoTxn) -> Result<bitcoin::Transaction> {
-        let withdrawals = self.locked_withdrawals.iter(txn).into_diagnostic()?;
-        let mut outputs = vec![];
+        let outpoints = match self.pending_bundles.first(txn).into_diagnostic()? {
+            Some((_m6id, bundle)) => bundle.outpoints,
+            None => vec![],
+        };
+        self.build_withdrawal_bundle(txn, &outpoints)
+    }
+
+    /// Shows what the next withdrawal bundle contains, without collecting or locking anything.
+    ///
+    /// Like [`Utxos::collect_withdrawals`], no withdrawals are selected during the grace window
+    /// after a failed bundle.
+    pub fn preview_withdrawal_bundle(&self, txn: &RoTxn) -> Result<BundlePreview> {
+        let main_block_height = self.get_main_block_height(txn)?;
+        let (outpoints, pending) = match self.pending_bundles.first(txn).into_diagnostic()? {
+            Some((_m6id, bundle)) => (bundle.outpoints, true),
+            None if self.is_in_grace_window(txn, main_block_height)? => (vec![], false),
+            None => (self.select_withdrawals(txn)?, false),
+        };
         let mut total_fee = 0;
-        for item in withdrawals {
-            let (outpoint, _) = item.into_diagnostic()?;
+        for outpoint in &outpoints {
             let output = self
                 .utxos
-                .get(txn, &outpoint)
+                .get(txn, outpoint)
+                .into_diagnostic()?
+                .ok_or(miette!("no utxo for outpoint"))?;
+            if let Output::Withdrawal { fee, .. } = output {
+                total_fee += fee;
+            }
+        }
+        let bundle = self.build_withdrawal_bundle(txn, &outpoints)?;
+        Ok(BundlePreview {
+            m6id: get_m6id(&bundle),
+            weight: bundle.weight().to_wu(),
+            bundle,
+            outpoints,
+            total_fee,
+            pending,
+        })
+    }
+
+    /// Builds the blinded bundle transaction paying out the withdrawals, which has no inputs and
+    /// commits to the total fee in an OP_RETURN output.
+    fn build_withdrawal_bundle(
+        &self,
+        txn: &RoTxn,
+        outpoints: &[OutPoint],
+    ) -> Result<bitcoin::Transaction> {
+        // Withdrawals to the same mainchain script are paid out by a single output, in the order in
+        // which the scripts first appear.
+        let mut scripts = vec![];
+        let mut script_values = HashMap::new();
+        let mut total_fee = 0;
+        for outpoint in outpoints {
+            let output = self
+                .utxos
+                .get(txn, outpoint)
                 .into_diagnostic()?
                 .ok_or(miette!("no utxo for outpoint"))?;
             match output {
@@ -290,18 +846,12 @@ impl Utxos {
                     fee,
                     ..
                 } => {
-                    let script_pubkey = bitcoin::blockdata::script::Builder::new()
-                        .push_opcode(OP_DUP)
-                        .push_opcode(OP_HASH160)
-                        .push_slice(main_address)
-                        .push_opcode(OP_EQUALVERIFY)
-                        .push_opcode(OP_CHECKSIG)
-                        .into_script();
-                    let output = TxOut {
-                        script_pubkey,
-                        value: bitcoin::Amount::from_sat(value),
-                    };
-                    outputs.push(output);
+                    let script = self.get_withdrawal_script(txn, outpoint, &main_address)?;
+                    let script_value = script_values.entry(script.clone()).or_insert_with(|| {
+                        scripts.push(script);
+                        0
+                    });
+                    *script_value += value;
                     total_fee += fee;
                 }
                 _ => {
@@ -309,6 +859,14 @@ impl Utxos {
                 }
             };
         }
+        let mut outputs = vec![];
+        for script_pubkey in scripts {
+            let value = bitcoin::Amount::from_sat(script_values[&script_pubkey]);
+            outputs.push(TxOut {
+                script_pubkey,
+                value,
+            });
+        }
         let fee_output = {
             let f_total_be_bytes = total_fee.to_be_bytes();
             let script_pubkey = bitcoin::ScriptBuf::from_bytes(
@@ -329,25 +887,73 @@ impl Utxos {
         Ok(bundle)
     }
 
+    /// Locks withdrawals into a new pending bundle, identified by its m6id.
     pub fn collect_withdrawals(&self, txn: &mut RwTxn) -> Result<()> {
-        if !self.locked_withdrawals.is_empty(txn).into_diagnostic()? {
+        if !self.pending_bundles.is_empty(txn).into_diagnostic()? {
             // Withdrawal bundle was already collected.
             return Ok(());
         }
-        pub const BUNDLE_FAILURE_GRACE_WINDOW: usize = 24 * 6; // 1 Day
-        pub const MAX_PENDING_BUNDLE_AGE: usize = 10 * 24 * 6; // 10 Days
         let main_block_height = self
             .main_block_height
             .get(txn, &UnitKey)
             .into_diagnostic()?
             .ok_or(miette!("no main block height"))?;
-        self.bundle_collection_main_height
-            .put(txn, &UnitKey, &main_block_height)
+        if self.is_in_grace_window(txn, main_block_height)? {
+            // Previous bundle failed recently.
+            return Ok(());
+        }
+        let outpoints = self.select_withdrawals(txn)?;
+        if outpoints.is_empty() {
+            return Ok(());
+        }
+        let m6id = get_m6id(&self.build_withdrawal_bundle(txn, &outpoints)?);
+        for outpoint in &outpoints {
+            self.unlocked_withdrawals
+                .delete(txn, outpoint)
+                .into_diagnostic()?;
+            self.locked_withdrawals
+                .put(txn, outpoint, &())
+                .into_diagnostic()?;
+            self.push_withdrawal_transition(
+                txn,
+                outpoint,
+                WithdrawalState::Locked,
+                Some(m6id),
+                main_block_height,
+            )?;
+        }
+        let pending_bundle = PendingBundle {
+            outpoints,
+            collection_main_height: main_block_height,
+            submitted: false,
+        };
+        self.pending_bundles
+            .put(txn, &m6id, &pending_bundle)
             .into_diagnostic()?;
+        Ok(())
+    }
+
+    /// True if the previous bundle failed less than BUNDLE_FAILURE_GRACE_WINDOW main blocks ago.
+    fn is_in_grace_window(&self, txn: &RoTxn, main_block_height: u32) -> Result<bool> {
+        let bundle_failure_main_height = self
+            .bundle_failure_main_height
+            .get(txn, &UnitKey)
+            .into_diagnostic()?;
+        let in_grace_window = match bundle_failure_main_height {
+            Some(bundle_failure_main_height) => {
+                main_block_height < bundle_failure_main_height + BUNDLE_FAILURE_GRACE_WINDOW
+            }
+            None => false,
+        };
+        Ok(in_grace_window)
+    }
+
+    /// Selects the unlocked withdrawals that go into the next bundle, in the order they are paid
+    /// out.
+    fn select_withdrawals(&self, txn: &RoTxn) -> Result<Vec<OutPoint>> {
         let mut bundle = vec![];
         for item in self.unlocked_withdrawals.iter(txn).into_diagnostic()? {
             let (outpoint, ()) = item.into_diagnostic()?;
-            dbg!(&outpoint);
             let output = self
                 .utxos
                 .get(txn, &outpoint)
@@ -355,9 +961,14 @@ impl Utxos {
                 .ok_or(miette!("no withdrawal utxo"))?;
             bundle.push((outpoint, output));
         }
-        // TODO: Aggregate withdrawals to the same address.
-        // FIXME: Figure out if this is determenistic.
-        bundle.sort_unstable_by(|(_, a), (_, b)| {
+        // Every node must build the same bundle, so withdrawals are ordered by fee descending,
+        // and ties keep the order in which unlocked withdrawals are iterated, since the sort is
+        // stable.
+        //
+        // That is the order of their keys, which are bincode encoded outpoints. Bincode encodes
+        // integers little endian, so it isn't the numeric order of outpoints, but it is the same
+        // on every node.
+        bundle.sort_by(|(_, a), (_, b)| {
             let a_fee = match a {
                 Output::Withdrawal { fee, .. } => fee,
                 _ => {
@@ -370,70 +981,912 @@ impl Utxos {
                     panic!("not a withdrawal");
                 }
             };
-            a_fee.cmp(b_fee)
+            b_fee.cmp(a_fee)
         });
-        const MAX_WITHDRAWAL_BUNDLE_OUTPUTS: usize = 6000;
-        let bundle: Vec<_> = bundle
-            .into_iter()
-            .take(MAX_WITHDRAWAL_BUNDLE_OUTPUTS)
-            .collect();
-        for (outpoint, _) in &bundle {
-            self.unlocked_withdrawals
-                .delete(txn, outpoint)
-                .into_diagnostic()?;
-            self.locked_withdrawals
-                .put(txn, outpoint, &())
-                .into_diagnostic()?
+        // Withdrawals to the same mainchain script share an output, so only distinct scripts count
+        // towards the output and weight limits.
+        //
+        // Withdrawals that are dust on their own, or that don't fit, stay unlocked and can go into
+        // a later bundle.
+        let mut scripts = HashSet::new();
+        let mut weight = self.build_withdrawal_bundle(txn, &[])?.weight().to_wu();
+        let mut selected = vec![];
+        for (outpoint, output) in bundle {
+            let Output::Withdrawal {
+                main_address,
+                value,
+                ..
+            } = output
+            else {
+                continue;
+            };
+            let script_pubkey = self.get_withdrawal_script(txn, &outpoint, &main_address)?;
+            if value < script_pubkey.minimal_non_dust().to_sat() {
+                continue;
+            }
+            if scripts.contains(&script_pubkey) {
+                selected.push(outpoint);
+                continue;
+            }
+            // The number of outputs is encoded as a varint, which gets longer as outputs are added.
+            let outputs_len = scripts.len() as u64 + 1;
+            let varint_growth = VarInt(outputs_len + 1).size() - VarInt(outputs_len).size();
+            let output_weight = TxOut {
+                script_pubkey: script_pubkey.clone(),
+                value: bitcoin::Amount::from_sat(value),
+            }
+            .weight()
+            .to_wu()
+                + (varint_growth * WITNESS_SCALE_FACTOR) as u64;
+            if scripts.len() < MAX_WITHDRAWAL_BUNDLE_OUTPUTS
+                && weight + output_weight <= MAX_WITHDRAWAL_BUNDLE_WEIGHT
+            {
+                scripts.insert(script_pubkey);
+                weight += output_weight;
+                selected.push(outpoint);
+            }
         }
-        Ok(())
+        Ok(selected)
     }
 
-    pub fn get_transaction_fee(&self, txn: &RoTxn, transaction: &Transaction) -> Result<u64> {
-        let mut value_in = 0;
-        for input in &transaction.inputs {
-            let spent_utxo = self
-                .utxos
-                .get(&txn, &input)
-                .into_diagnostic()?
-                .ok_or(miette!("transaction is invalid"))?;
-            value_in += spent_utxo.total_value();
-        }
-        let value_out = transaction.value_out();
-        if value_in < value_out {
-            return Err(miette!("transaction is invalid"));
+    /// Returns the utxo at an outpoint, if it exists and isn't a locked withdrawal.
+    pub fn get_spendable_output(&self, txn: &RoTxn, outpoint: &OutPoint) -> Result<Output> {
+        if self.is_locked_withdrawal(txn, outpoint)? {
+            return Err(TransactionError::LockedWithdrawal(outpoint.clone()).into());
         }
-        Ok(value_in - value_out)
+        let output = self
+            .utxos
+            .get(txn, outpoint)
+            .into_diagnostic()?
+            .ok_or(TransactionError::MissingInput(outpoint.clone()))?;
+        Ok(output)
+    }
+
+    /// Script paying out a withdrawal, to its destination if it has one, or else to P2PKH of its
+    /// `main_address`.
+    fn get_withdrawal_script(
+        &self,
+        txn: &RoTxn,
+        outpoint: &OutPoint,
+        main_address: &[u8; 20],
+    ) -> Result<ScriptBuf> {
+        let destination = self
+            .withdrawal_destinations
+            .get(txn, outpoint)
+            .into_diagnostic()?
+            .unwrap_or(WithdrawalDestination::P2pkh(*main_address));
+        Ok(destination.script_pubkey())
     }
 
+    /// Unlocked withdrawals aren't in a bundle yet, so they can still be cancelled.
+    pub fn is_unlocked_withdrawal(&self, txn: &RoTxn, outpoint: &OutPoint) -> Result<bool> {
+        let unlocked = self
+            .unlocked_withdrawals
+            .get(txn, outpoint)
+            .into_diagnostic()?
+            .is_some();
+        Ok(unlocked)
+    }
+
+    /// Locked withdrawals are committed to a pending bundle, so they can't be spent on the
+    /// sidechain until the bundle fails and they are unlocked again.
+    fn is_locked_withdrawal(&self, txn: &RoTxn, outpoint: &OutPoint) -> Result<bool> {
+        let locked = self
+            .locked_withdrawals
+            .get(txn, outpoint)
+            .into_diagnostic()?
+            .is_some();
+        Ok(locked)
+    }
+
+    /// Returns the addresses of the outputs spent by the transactions of a block, which may spend
+    /// outputs of earlier transactions in the same block, like [`Utxos::validate`] allows.
     pub fn extract_input_addresses(
         &self,
         txn: &RoTxn,
-        transactions: &[Transaction],
+        transactions: &[AuthorizedTransaction],
     ) -> Result<Vec<[u8; ADDRESS_LENGTH]>> {
+        let mut created = HashMap::new();
+        let mut transaction_number = self.get_next_transaction_number(txn)?;
         let mut addresses = vec![];
         for transaction in transactions {
-            for input in &transaction.inputs {
-                let output = self
-                    .utxos
-                    .get(txn, input)
-                    .into_diagnostic()?
-                    .ok_or(miette!("input {input} doesn't exist"))?;
-                let address = output.address();
+            for input in &transaction.transaction.inputs {
+                let address = match created.get(input) {
+                    Some(address) => *address,
+                    None => self
+                        .utxos
+                        .get(txn, input)
+                        .into_diagnostic()?
+                        .ok_or(miette!("input {input} doesn't exist"))?
+                        .address(),
+                };
                 addresses.push(address);
             }
+            for (output_number, output) in transaction.transaction.outputs.iter().enumerate() {
+                let outpoint = OutPoint::Regular {
+                    transaction_number,
+                    output_number: output_number as u8,
+                };
+                created.insert(outpoint, output.address());
+            }
+            transaction_number += 1;
         }
         Ok(addresses)
     }
 
-    pub fn submit_bundle(&self, txn: &mut RwTxn, m6id: &[u8; HASH_LENGTH]) -> Result<()> {
-        todo!();
+    /// Marks the pending bundle as submitted to the mainchain.
+    pub fn submit_bundle(
+        &self,
+        txn: &mut RwTxn,
+        m6id: &[u8; HASH_LENGTH],
+        main_block_height: u32,
+    ) -> Result<BundleEventUndo> {
+        let Some(mut bundle) = self.pending_bundles.get(txn, m6id).into_diagnostic()? else {
+            return Ok(BundleEventUndo::Ignored);
+        };
+        if bundle.submitted {
+            return Ok(BundleEventUndo::Ignored);
+        }
+        bundle.submitted = true;
+        self.pending_bundles
+            .put(txn, m6id, &bundle)
+            .into_diagnostic()?;
+        for outpoint in &bundle.outpoints {
+            self.push_withdrawal_transition(
+                txn,
+                outpoint,
+                WithdrawalState::Submitted,
+                Some(*m6id),
+                main_block_height,
+            )?;
+        }
+        Ok(BundleEventUndo::Submitted { m6id: *m6id })
     }
 
-    pub fn succeed_bundle(&self, txn: &mut RwTxn, m6id: &[u8; HASH_LENGTH]) -> Result<()> {
-        todo!();
+    /// Permanently removes the withdrawals paid out by the bundle from the utxo set.
+    pub fn succeed_bundle(
+        &self,
+        txn: &mut RwTxn,
+        m6id: &[u8; HASH_LENGTH],
+        main_block_height: u32,
+    ) -> Result<BundleEventUndo> {
+        let Some(bundle) = self.pending_bundles.get(txn, m6id).into_diagnostic()? else {
+            return Ok(BundleEventUndo::Ignored);
+        };
+        let mut spent = vec![];
+        let mut utxo_set_hash = self.get_utxo_set_hash(txn)?;
+        for outpoint in &bundle.outpoints {
+            let output = self
+                .delete_utxo(txn, &mut utxo_set_hash, outpoint)?
+                .ok_or(miette!("no withdrawal utxo"))?;
+            self.locked_withdrawals
+                .delete(txn, outpoint)
+                .into_diagnostic()?;
+            self.push_withdrawal_transition(
+                txn,
+                outpoint,
+                WithdrawalState::PaidOut,
+                Some(*m6id),
+                main_block_height,
+            )?;
+            spent.push(output);
+        }
+        self.put_utxo_set_hash(txn, &utxo_set_hash)?;
+        self.pending_bundles.delete(txn, m6id).into_diagnostic()?;
+        Ok(BundleEventUndo::Succeeded {
+            m6id: *m6id,
+            bundle,
+            spent,
+        })
     }
 
-    pub fn fail_bundle(&self, txn: &mut RwTxn, m6id: &[u8; HASH_LENGTH]) -> Result<()> {
-        todo!();
+    /// Releases the withdrawals of the bundle back to unlocked withdrawals, so they can be
+    /// included in the next bundle.
+    pub fn fail_bundle(
+        &self,
+        txn: &mut RwTxn,
+        m6id: &[u8; HASH_LENGTH],
+        main_block_height: u32,
+    ) -> Result<BundleEventUndo> {
+        let Some(bundle) = self.pending_bundles.get(txn, m6id).into_diagnostic()? else {
+            return Ok(BundleEventUndo::Ignored);
+        };
+        for outpoint in &bundle.outpoints {
+            self.locked_withdrawals
+                .delete(txn, outpoint)
+                .into_diagnostic()?;
+            self.unlocked_withdrawals
+                .put(txn, outpoint, &())
+                .into_diagnostic()?;
+            self.push_withdrawal_transition(
+                txn,
+                outpoint,
+                WithdrawalState::Returned,
+                Some(*m6id),
+                main_block_height,
+            )?;
+        }
+        self.pending_bundles.delete(txn, m6id).into_diagnostic()?;
+        let prev_bundle_failure_main_height = self
+            .bundle_failure_main_height
+            .get(txn, &UnitKey)
+            .into_diagnostic()?;
+        self.bundle_failure_main_height
+            .put(txn, &UnitKey, &main_block_height)
+            .into_diagnostic()?;
+        Ok(BundleEventUndo::Failed {
+            m6id: *m6id,
+            bundle,
+            prev_bundle_failure_main_height,
+        })
+    }
+
+    /// Drops the pending bundle that locked a withdrawal, because the block that created the
+    /// withdrawal is being disconnected.
+    ///
+    /// The other withdrawals of the bundle are unlocked again, so they can go into the next bundle.
+    /// Unlike a failure, this doesn't start the grace window, since the mainchain never saw the
+    /// bundle fail. Main block events for the dropped bundle are ignored from then on, like those
+    /// for any other unknown bundle.
+    ///
+    /// Submitted bundles can't be dropped, since the mainchain may still pay them out, and the
+    /// withdrawals they unlock could then be paid out twice.
+    fn drop_bundle(&self, txn: &mut RwTxn, withdrawal: &OutPoint) -> Result<()> {
+        let mut dropped = None;
+        for item in self.pending_bundles.iter(txn).into_diagnostic()? {
+            let (m6id, bundle) = item.into_diagnostic()?;
+            if bundle.outpoints.contains(withdrawal) {
+                dropped = Some((m6id, bundle));
+                break;
+            }
+        }
+        let (m6id, bundle) =
+            dropped.ok_or(miette!("no pending bundle locks withdrawal {withdrawal}"))?;
+        if bundle.submitted {
+            return Err(miette!(
+                "can't drop bundle {}, it was submitted to the mainchain",
+                hex::encode(m6id)
+            ));
+        }
+        let main_block_height = self.get_main_block_height(txn)?;
+        for outpoint in &bundle.outpoints {
+            self.locked_withdrawals
+                .delete(txn, outpoint)
+                .into_diagnostic()?;
+            self.unlocked_withdrawals
+                .put(txn, outpoint, &())
+                .into_diagnostic()?;
+            self.push_withdrawal_transition(
+                txn,
+                outpoint,
+                WithdrawalState::Returned,
+                Some(m6id),
+                main_block_height,
+            )?;
+        }
+        self.pending_bundles.delete(txn, &m6id).into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Fails the pending bundle, if it was collected more than MAX_PENDING_BUNDLE_AGE main blocks
+    /// ago and wasn't submitted.
+    ///
+    /// Submitted bundles are up to the mainchain, which fails them with a main block event.
+    pub fn expire_bundle(
+        &self,
+        txn: &mut RwTxn,
+        main_block_height: u32,
+    ) -> Result<Option<BundleEventUndo>> {
+        let Some((m6id, bundle)) = self.pending_bundles.first(txn).into_diagnostic()? else {
+            return Ok(None);
+        };
+        let age = main_block_height.saturating_sub(bundle.collection_main_height);
+        if bundle.submitted || age <= MAX_PENDING_BUNDLE_AGE {
+            return Ok(None);
+        }
+        let undo = self.fail_bundle(txn, &m6id, main_block_height)?;
+        Ok(Some(undo))
+    }
+
+    /// Returns the transitions of a withdrawal, oldest first, so the last one is its current
+    /// state.
+    pub fn get_withdrawal_status(
+        &self,
+        txn: &RoTxn,
+        outpoint: &OutPoint,
+    ) -> Result<Option<Vec<WithdrawalTransition>>> {
+        let transitions = self
+            .withdrawal_statuses
+            .get(txn, outpoint)
+            .into_diagnostic()?;
+        Ok(transitions)
+    }
+
+    fn push_withdrawal_transition(
+        &self,
+        txn: &mut RwTxn,
+        outpoint: &OutPoint,
+        state: WithdrawalState,
+        m6id: Option<[u8; HASH_LENGTH]>,
+        main_block_height: u32,
+    ) -> Result<()> {
+        let mut transitions = self
+            .withdrawal_statuses
+            .get(txn, outpoint)
+            .into_diagnostic()?
+            .unwrap_or_default();
+        transitions.push(WithdrawalTransition {
+            state,
+            m6id,
+            main_block_height,
+        });
+        self.withdrawal_statuses
+            .put(txn, outpoint, &transitions)
+            .into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Reverts the last [`Utxos::push_withdrawal_transition`] for the withdrawal.
+    fn pop_withdrawal_transition(&self, txn: &mut RwTxn, outpoint: &OutPoint) -> Result<()> {
+        let mut transitions = self
+            .withdrawal_statuses
+            .get(txn, outpoint)
+            .into_diagnostic()?
+            .ok_or(miette!("no status for withdrawal {outpoint}"))?;
+        transitions.pop();
+        self.withdrawal_statuses
+            .put(txn, outpoint, &transitions)
+            .into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Reverts the changes made by [`Utxos::submit_bundle`], [`Utxos::succeed_bundle`] or
+    /// [`Utxos::fail_bundle`].
+    pub fn undo_bundle_event(&self, txn: &mut RwTxn, undo: &BundleEventUndo) -> Result<()> {
+        match undo {
+            BundleEventUndo::Ignored => {}
+            BundleEventUndo::Submitted { m6id } => {
+                // Submitted bundles are never dropped, see `Utxos::drop_bundle`.
+                let mut bundle = self
+                    .pending_bundles
+                    .get(txn, m6id)
+                    .into_diagnostic()?
+                    .ok_or(miette!("no pending bundle {}", hex::encode(m6id)))?;
+                bundle.submitted = false;
+                self.pending_bundles
+                    .put(txn, m6id, &bundle)
+                    .into_diagnostic()?;
+                for outpoint in &bundle.outpoints {
+                    self.pop_withdrawal_transition(txn, outpoint)?;
+                }
+            }
+            BundleEventUndo::Succeeded {
+                m6id,
+                bundle,
+                spent,
+            } => {
+                let mut utxo_set_hash = self.get_utxo_set_hash(txn)?;
+                for (outpoint, output) in bundle.outpoints.iter().zip(spent) {
+                    self.insert_utxo(txn, &mut utxo_set_hash, outpoint, output)?;
+                    self.locked_withdrawals
+                        .put(txn, outpoint, &())
+                        .into_diagnostic()?;
+                    self.pop_withdrawal_transition(txn, outpoint)?;
+                }
+                self.put_utxo_set_hash(txn, &utxo_set_hash)?;
+                self.pending_bundles
+                    .put(txn, m6id, bundle)
+                    .into_diagnostic()?;
+            }
+            BundleEventUndo::Failed {
+                m6id,
+                bundle,
+                prev_bundle_failure_main_height,
+            } => {
+                for outpoint in &bundle.outpoints {
+                    self.unlocked_withdrawals
+                        .delete(txn, outpoint)
+                        .into_diagnostic()?;
+                    self.locked_withdrawals
+                        .put(txn, outpoint, &())
+                        .into_diagnostic()?;
+                    self.pop_withdrawal_transition(txn, outpoint)?;
+                }
+                self.pending_bundles
+                    .put(txn, m6id, bundle)
+                    .into_diagnostic()?;
+                match prev_bundle_failure_main_height {
+                    Some(height) => {
+                        self.bundle_failure_main_height
+                            .put(txn, &UnitKey, height)
+                            .into_diagnostic()?;
+                    }
+                    None => {
+                        self.bundle_failure_main_height
+                            .delete(txn, &UnitKey)
+                            .into_diagnostic()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Raw contents of every database, for exact comparisons in tests.
+    #[cfg(test)]
+    pub fn dump(&self, txn: &RoTxn) -> Vec<crate::test_utils::Dump> {
+        use crate::test_utils::dump_db;
+        vec![
+            dump_db(txn, &self.utxos),
+            dump_db(txn, &self.utxo_set_hash),
+            dump_db(txn, &self.utxo_set_commitments),
+            dump_db(txn, &self.transaction_number),
+            dump_db(txn, &self.main_block_height),
+            dump_db(txn, &self.main_chain_tip),
+            dump_db(txn, &self.side_block_height),
+            dump_db(txn, &self.unlocked_withdrawals),
+            dump_db(txn, &self.locked_withdrawals),
+            dump_db(txn, &self.pending_bundles),
+            dump_db(txn, &self.withdrawal_statuses),
+            dump_db(txn, &self.withdrawal_destinations),
+            dump_db(txn, &self.bundle_failure_main_height),
+            dump_db(txn, &self.block_undos),
+            dump_db(txn, &self.main_block_undos),
+        ]
+    }
+}
+
+/// M6ID is the txid of the blinded bundle transaction, which is how the enforcer identifies
+/// bundles.
+fn get_m6id(bundle: &bitcoin::Transaction) -> [u8; HASH_LENGTH] {
+    bundle.compute_txid().to_byte_array()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_env, unsigned};
+    use cusf_sidechain_types::Transaction;
+
+    const ALICE: [u8; ADDRESS_LENGTH] = [1; ADDRESS_LENGTH];
+    const BOB: [u8; ADDRESS_LENGTH] = [2; ADDRESS_LENGTH];
+
+    fn new_utxos() -> (tempfile::TempDir, Env, Utxos) {
+        let (dir, env) = new_env(Utxos::NUM_DBS);
+        let utxos = Utxos::new(&env).unwrap();
+        (dir, env, utxos)
+    }
+
+    fn deposit(sequence_number: u64, value: u64) -> (OutPoint, Output) {
+        let outpoint = OutPoint::Deposit { sequence_number };
+        let output = Output::Regular {
+            address: ALICE,
+            value,
+        };
+        (outpoint, output)
+    }
+
+    fn withdrawal(main_address: [u8; 20], value: u64) -> Output {
+        Output::Withdrawal {
+            address: ALICE,
+            main_address,
+            value,
+            fee: 1_000,
+        }
+    }
+
+    #[test]
+    fn disconnect_restores_connect() {
+        let (_dir, env, utxos) = new_utxos();
+        let mut txn = env.write_txn().unwrap();
+        utxos
+            .add_utxos(&mut txn, &[deposit(0, 500_000), deposit(1, 500_000)])
+            .unwrap();
+        // Creates a withdrawal, which the second block cancels by spending it. The second block
+        // also creates a withdrawal and cancels it right away.
+        let first_block = [unsigned(Transaction {
+            inputs: vec![deposit(0, 0).0],
+            outputs: vec![
+                Output::Regular {
+                    address: BOB,
+                    value: 100_000,
+                },
+                withdrawal([3; 20], 300_000),
+            ],
+        })];
+        utxos.connect(&mut txn, 1, &[], &first_block).unwrap();
+        let before = utxos.dump(&txn);
+        let second_block = [
+            unsigned(Transaction {
+                inputs: vec![OutPoint::Regular {
+                    transaction_number: 0,
+                    output_number: 1,
+                }],
+                outputs: vec![Output::Regular {
+                    address: ALICE,
+                    value: 250_000,
+                }],
+            }),
+            AuthorizedTransaction {
+                transaction: Transaction {
+                    inputs: vec![deposit(1, 0).0],
+                    outputs: vec![withdrawal([4; 20], 400_000)],
+                },
+                withdrawal_destinations: vec![WithdrawalDestination::P2wsh([5; 32])],
+                unconfirmed_inputs: vec![],
+                authorizations: vec![],
+            },
+            unsigned(Transaction {
+                inputs: vec![OutPoint::Regular {
+                    transaction_number: 2,
+                    output_number: 0,
+                }],
+                outputs: vec![Output::Regular {
+                    address: ALICE,
+                    value: 400_000,
+                }],
+            }),
+        ];
+        let coinbase = [Output::Regular {
+            address: BOB,
+            value: 50_000,
+        }];
+        utxos.validate(&txn, &coinbase, &second_block).unwrap();
+        utxos
+            .connect(&mut txn, 2, &coinbase, &second_block)
+            .unwrap();
+        assert_ne!(utxos.dump(&txn), before);
+        utxos.disconnect(&mut txn, 2).unwrap();
+        assert_eq!(utxos.dump(&txn), before);
+    }
+
+    #[test]
+    fn disconnect_drops_bundle_locking_its_withdrawals() {
+        let (_dir, env, utxos) = new_utxos();
+        let mut txn = env.write_txn().unwrap();
+        utxos.set_main_block_height(&mut txn, 100).unwrap();
+        utxos
+            .add_utxos(&mut txn, &[deposit(0, 500_000), deposit(1, 500_000)])
+            .unwrap();
+        let first_block = [unsigned(Transaction {
+            inputs: vec![deposit(0, 0).0],
+            outputs: vec![withdrawal([3; 20], 400_000)],
+        })];
+        utxos.connect(&mut txn, 1, &[], &first_block).unwrap();
+        let second_block = [unsigned(Transaction {
+            inputs: vec![deposit(1, 0).0],
+            outputs: vec![withdrawal([4; 20], 400_000)],
+        })];
+        utxos.connect(&mut txn, 2, &[], &second_block).unwrap();
+        utxos.collect_withdrawals(&mut txn).unwrap();
+        let kept = OutPoint::Regular {
+            transaction_number: 0,
+            output_number: 0,
+        };
+        let disconnected = OutPoint::Regular {
+            transaction_number: 1,
+            output_number: 0,
+        };
+        assert!(utxos.is_locked_withdrawal(&txn, &kept).unwrap());
+        assert!(utxos.is_locked_withdrawal(&txn, &disconnected).unwrap());
+
+        utxos.disconnect(&mut txn, 2).unwrap();
+        assert!(utxos.pending_bundles.is_empty(&txn).unwrap());
+        assert!(utxos.locked_withdrawals.is_empty(&txn).unwrap());
+        let unlocked: Vec<_> = utxos
+            .unlocked_withdrawals
+            .iter(&txn)
+            .unwrap()
+            .map(|item| item.unwrap().0)
+            .collect();
+        assert_eq!(unlocked, vec![kept.clone()]);
+        let states: Vec<_> = utxos
+            .get_withdrawal_status(&txn, &kept)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|transition| transition.state)
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                WithdrawalState::Unlocked,
+                WithdrawalState::Locked,
+                WithdrawalState::Returned
+            ]
+        );
+        assert!(utxos
+            .get_withdrawal_status(&txn, &disconnected)
+            .unwrap()
+            .is_none());
+        // The remaining withdrawal can be collected and paid out again.
+        utxos.collect_withdrawals(&mut txn).unwrap();
+        let (m6id, _bundle) = utxos.pending_bundles.first(&txn).unwrap().unwrap();
+        utxos.succeed_bundle(&mut txn, &m6id, 101).unwrap();
+        assert!(utxos.utxos.get(&txn, &kept).unwrap().is_none());
+    }
+
+    #[test]
+    fn disconnect_keeps_submitted_bundle() {
+        let (_dir, env, utxos) = new_utxos();
+        let mut txn = env.write_txn().unwrap();
+        utxos.set_main_block_height(&mut txn, 100).unwrap();
+        utxos.add_utxos(&mut txn, &[deposit(0, 500_000)]).unwrap();
+        let block = [unsigned(Transaction {
+            inputs: vec![deposit(0, 0).0],
+            outputs: vec![withdrawal([3; 20], 400_000)],
+        })];
+        utxos.connect(&mut txn, 1, &[], &block).unwrap();
+        utxos.collect_withdrawals(&mut txn).unwrap();
+        let (m6id, bundle) = utxos.pending_bundles.first(&txn).unwrap().unwrap();
+        utxos.submit_bundle(&mut txn, &m6id, 101).unwrap();
+
+        assert!(utxos.disconnect(&mut txn, 1).is_err());
+        assert!(utxos.pending_bundles.get(&txn, &m6id).unwrap().is_some());
+        assert!(utxos
+            .is_locked_withdrawal(&txn, &bundle.outpoints[0])
+            .unwrap());
+    }
+
+    /// Adds a withdrawal to the utxo set and to unlocked withdrawals, like a block would.
+    fn add_withdrawal(
+        utxos: &Utxos,
+        txn: &mut RwTxn,
+        outpoint: &OutPoint,
+        main_address: [u8; 20],
+        value: u64,
+        fee: u64,
+    ) {
+        let output = Output::Withdrawal {
+            address: ALICE,
+            main_address,
+            value,
+            fee,
+        };
+        utxos.add_utxos(txn, &[(outpoint.clone(), output)]).unwrap();
+        utxos.unlocked_withdrawals.put(txn, outpoint, &()).unwrap();
+    }
+
+    #[test]
+    fn withdrawals_are_selected_by_fee_then_key_order() {
+        let (_dir, env, utxos) = new_utxos();
+        let mut txn = env.write_txn().unwrap();
+        let outpoint = |transaction_number| OutPoint::Regular {
+            transaction_number,
+            output_number: 0,
+        };
+        for (transaction_number, fee) in [(1, 2_000), (256, 2_000), (2, 3_000), (3, 1_000)] {
+            let main_address = [transaction_number as u8; 20];
+            add_withdrawal(
+                &utxos,
+                &mut txn,
+                &outpoint(transaction_number),
+                main_address,
+                100_000,
+                fee,
+            );
+        }
+        let selected = utxos.select_withdrawals(&txn).unwrap();
+        // Transaction number 256 is encoded as [0, 1, 0, ...], so its key comes before the one of
+        // transaction number 1.
+        assert_eq!(
+            selected,
+            vec![outpoint(2), outpoint(256), outpoint(1), outpoint(3)]
+        );
+    }
+
+    #[test]
+    fn bundle_weight_is_capped_and_dust_is_skipped() {
+        let (_dir, env, utxos) = new_utxos();
+        let mut txn = env.write_txn().unwrap();
+        let outpoint = |transaction_number| OutPoint::Regular {
+            transaction_number,
+            output_number: 0,
+        };
+        let dust = outpoint(0);
+        add_withdrawal(&utxos, &mut txn, &dust, [0xff; 20], 500, 10_000);
+        // More P2PKH outputs than fit in the weight limit, which is reached long before the
+        // output limit.
+        let main_address = |index: u64| {
+            let mut main_address = [0; 20];
+            main_address[..8].copy_from_slice(&index.to_le_bytes());
+            main_address
+        };
+        for index in 1..=3_000 {
+            let main_address = main_address(index);
+            add_withdrawal(
+                &utxos,
+                &mut txn,
+                &outpoint(index),
+                main_address,
+                10_000,
+                2_000,
+            );
+        }
+        // Sorted last, but shares the output of the first withdrawal, so it still fits.
+        let shared = outpoint(3_001);
+        add_withdrawal(&utxos, &mut txn, &shared, main_address(1), 10_000, 1);
+
+        let selected = utxos.select_withdrawals(&txn).unwrap();
+        assert!(!selected.contains(&dust));
+        assert!(selected.len() < 3_001);
+        assert_eq!(selected.last(), Some(&shared));
+        let bundle = utxos.build_withdrawal_bundle(&txn, &selected).unwrap();
+        let weight = bundle.weight().to_wu();
+        let p2pkh_output_weight = TxOut {
+            script_pubkey: WithdrawalDestination::P2pkh([0; 20]).script_pubkey(),
+            value: bitcoin::Amount::ZERO,
+        }
+        .weight()
+        .to_wu();
+        assert!(weight <= MAX_WITHDRAWAL_BUNDLE_WEIGHT);
+        assert!(weight + p2pkh_output_weight > MAX_WITHDRAWAL_BUNDLE_WEIGHT);
+    }
+
+    #[test]
+    fn bundle_pays_out_to_withdrawal_destinations() {
+        let (_dir, env, utxos) = new_utxos();
+        let mut txn = env.write_txn().unwrap();
+        utxos.set_main_block_height(&mut txn, 100).unwrap();
+        let (outpoint, output) = deposit(0, 1_000_000);
+        utxos
+            .add_utxos(&mut txn, &[(outpoint.clone(), output)])
+            .unwrap();
+        let destination = WithdrawalDestination::P2wpkh([3; 20]);
+        // The second and third withdrawals share a destination, the first one has the same
+        // `main_address` but pays out to P2PKH.
+        let block = [AuthorizedTransaction {
+            transaction: Transaction {
+                inputs: vec![outpoint],
+                outputs: vec![
+                    withdrawal([3; 20], 100_000),
+                    withdrawal([3; 20], 200_000),
+                    withdrawal([4; 20], 300_000),
+                ],
+            },
+            withdrawal_destinations: vec![
+                WithdrawalDestination::P2pkh([3; 20]),
+                destination,
+                destination,
+            ],
+            unconfirmed_inputs: vec![],
+            authorizations: vec![],
+        }];
+        utxos.connect(&mut txn, 1, &[], &block).unwrap();
+        utxos.collect_withdrawals(&mut txn).unwrap();
+        let bundle = utxos.get_withdrawal_bundle(&txn).unwrap();
+        let outputs: Vec<_> = bundle
+            .output
+            .iter()
+            .map(|output| (output.script_pubkey.clone(), output.value.to_sat()))
+            .collect();
+        assert_eq!(
+            outputs[..2],
+            [
+                (
+                    WithdrawalDestination::P2pkh([3; 20]).script_pubkey(),
+                    100_000
+                ),
+                (destination.script_pubkey(), 500_000),
+            ]
+        );
+        assert!(outputs[0].0.is_p2pkh());
+        assert!(outputs[1].0.is_p2wpkh());
+    }
+
+    #[test]
+    fn preview_selects_nothing_during_grace_window() {
+        let (_dir, env, utxos) = new_utxos();
+        let mut txn = env.write_txn().unwrap();
+        utxos.set_main_block_height(&mut txn, 100).unwrap();
+        let (outpoint, output) = deposit(0, 500_000);
+        utxos
+            .add_utxos(&mut txn, &[(outpoint.clone(), output)])
+            .unwrap();
+        let block = [unsigned(Transaction {
+            inputs: vec![outpoint],
+            outputs: vec![withdrawal([3; 20], 400_000)],
+        })];
+        utxos.connect(&mut txn, 1, &[], &block).unwrap();
+        utxos.collect_withdrawals(&mut txn).unwrap();
+        let (m6id, bundle) = utxos.pending_bundles.first(&txn).unwrap().unwrap();
+        utxos.fail_bundle(&mut txn, &m6id, 101).unwrap();
+
+        utxos.set_main_block_height(&mut txn, 101).unwrap();
+        let preview = utxos.preview_withdrawal_bundle(&txn).unwrap();
+        assert!(preview.outpoints.is_empty());
+        assert!(!preview.pending);
+
+        let main_block_height = 101 + BUNDLE_FAILURE_GRACE_WINDOW;
+        utxos
+            .set_main_block_height(&mut txn, main_block_height)
+            .unwrap();
+        let preview = utxos.preview_withdrawal_bundle(&txn).unwrap();
+        assert_eq!(preview.outpoints, bundle.outpoints);
+        assert_eq!(preview.m6id, m6id);
+    }
+
+    #[test]
+    fn only_unsubmitted_bundles_expire() {
+        let (_dir, env, utxos) = new_utxos();
+        let mut txn = env.write_txn().unwrap();
+        utxos.set_main_block_height(&mut txn, 100).unwrap();
+        let (outpoint, output) = deposit(0, 500_000);
+        utxos
+            .add_utxos(&mut txn, &[(outpoint.clone(), output)])
+            .unwrap();
+        let block = [unsigned(Transaction {
+            inputs: vec![outpoint],
+            outputs: vec![withdrawal([3; 20], 400_000)],
+        })];
+        utxos.connect(&mut txn, 1, &[], &block).unwrap();
+        utxos.collect_withdrawals(&mut txn).unwrap();
+        let (m6id, _bundle) = utxos.pending_bundles.first(&txn).unwrap().unwrap();
+        let main_block_height = 101 + MAX_PENDING_BUNDLE_AGE;
+
+        let submitted = utxos.submit_bundle(&mut txn, &m6id, 101).unwrap();
+        let expired = utxos.expire_bundle(&mut txn, main_block_height).unwrap();
+        assert!(expired.is_none());
+        assert!(utxos.pending_bundles.get(&txn, &m6id).unwrap().is_some());
+
+        utxos.undo_bundle_event(&mut txn, &submitted).unwrap();
+        let expired = utxos.expire_bundle(&mut txn, main_block_height).unwrap();
+        assert!(matches!(expired, Some(BundleEventUndo::Failed { .. })));
+        assert!(utxos.pending_bundles.is_empty(&txn).unwrap());
+    }
+
+    #[test]
+    fn utxo_set_hash_matches_rebuild() {
+        let (_dir, env, utxos) = new_utxos();
+        let mut txn = env.write_txn().unwrap();
+        utxos
+            .add_utxos(&mut txn, &[deposit(0, 500_000), deposit(1, 500_000)])
+            .unwrap();
+        // Deposits can't be added twice.
+        assert!(utxos.add_utxos(&mut txn, &[deposit(1, 1)]).is_err());
+        let block = [AuthorizedTransaction {
+            transaction: Transaction {
+                inputs: vec![deposit(0, 0).0],
+                outputs: vec![
+                    Output::Regular {
+                        address: BOB,
+                        value: 100_000,
+                    },
+                    withdrawal([3; 20], 100_000),
+                    withdrawal([3; 20], 200_000),
+                ],
+            },
+            withdrawal_destinations: vec![WithdrawalDestination::P2wpkh([3; 20])],
+            unconfirmed_inputs: vec![],
+            authorizations: vec![],
+        }];
+        let coinbase = [Output::Regular {
+            address: BOB,
+            value: 100_000,
+        }];
+        utxos.connect(&mut txn, 1, &coinbase, &block).unwrap();
+        utxos.remove_utxos(&mut txn, &[deposit(1, 0).0]).unwrap();
+        let utxo_set_hash = utxos.get_utxo_set_hash(&txn).unwrap();
+        utxos.rebuild_utxo_set_hash(&mut txn).unwrap();
+        assert_eq!(
+            utxos.get_utxo_set_hash(&txn).unwrap().commitment(),
+            utxo_set_hash.commitment()
+        );
+
+        // The same withdrawal with another destination pays out elsewhere, so it has to change
+        // the commitment.
+        let outpoint = OutPoint::Regular {
+            transaction_number: 0,
+            output_number: 1,
+        };
+        utxos
+            .withdrawal_destinations
+            .put(&mut txn, &outpoint, &WithdrawalDestination::P2wsh([3; 32]))
+            .unwrap();
+        utxos.rebuild_utxo_set_hash(&mut txn).unwrap();
+        assert_ne!(
+            utxos.get_utxo_set_hash(&txn).unwrap().commitment(),
+            utxo_set_hash.commitment()
+        );
     }
 }
@@ -0,0 +1,61 @@
+//! Fixtures shared by the unit tests of several modules.
+
+use cusf_sidechain_types::Transaction;
+use ed25519_dalek::{Signer, SigningKey};
+use heed::types::ByteSlice;
+use heed::{Database, Env, EnvOpenOptions, RoTxn};
+
+use crate::authorization::{Authorization, AuthorizedTransaction, UnconfirmedOutPoint};
+
+/// Opens an environment in a temporary directory, which is deleted when it is dropped.
+pub fn new_env(max_dbs: u32) -> (tempfile::TempDir, Env) {
+    let dir = tempfile::tempdir().unwrap();
+    let env = EnvOpenOptions::new()
+        .max_dbs(max_dbs)
+        .open(dir.path())
+        .unwrap();
+    (dir, env)
+}
+
+/// Raw contents of a database, for comparing states exactly.
+pub type Dump = Vec<(Vec<u8>, Vec<u8>)>;
+
+pub fn dump_db<KC: 'static, DC: 'static>(txn: &RoTxn, db: &Database<KC, DC>) -> Dump {
+    db.remap_types::<ByteSlice, ByteSlice>()
+        .iter(txn)
+        .unwrap()
+        .map(|item| {
+            let (key, value) = item.unwrap();
+            (key.to_vec(), value.to_vec())
+        })
+        .collect()
+}
+
+/// Transaction without withdrawal destinations, unconfirmed inputs or authorizations, for code
+/// that doesn't check authorizations.
+pub fn unsigned(transaction: Transaction) -> AuthorizedTransaction {
+    AuthorizedTransaction {
+        transaction,
+        withdrawal_destinations: vec![],
+        unconfirmed_inputs: vec![],
+        authorizations: vec![],
+    }
+}
+
+/// Authorizes every input, including the unconfirmed ones, with the same key.
+pub fn authorize(
+    signing_key: &SigningKey,
+    transaction: Transaction,
+    unconfirmed_inputs: Vec<UnconfirmedOutPoint>,
+) -> AuthorizedTransaction {
+    let mut transaction = AuthorizedTransaction {
+        unconfirmed_inputs,
+        ..unsigned(transaction)
+    };
+    let authorization = Authorization {
+        verifying_key: signing_key.verifying_key(),
+        signature: signing_key.sign(&transaction.hash()),
+    };
+    transaction.authorizations = vec![authorization; transaction.inputs_len()];
+    transaction
+}
@@ -0,0 +1,94 @@
+use bitcoin::blockdata::script::Builder;
+use bitcoin::opcodes::all::{
+    OP_CHECKSIG, OP_DUP, OP_EQUAL, OP_EQUALVERIFY, OP_HASH160, OP_PUSHNUM_1,
+};
+use bitcoin::opcodes::OP_0;
+use bitcoin::secp256k1::XOnlyPublicKey;
+use bitcoin::ScriptBuf;
+use cusf_sidechain_types::Output;
+use serde::{Deserialize, Serialize};
+
+use crate::authorization::AuthorizedTransaction;
+use crate::state::error::TransactionError;
+
+/// Mainchain script type a withdrawal pays out to, with the hash or key it commits to.
+///
+/// `Output::Withdrawal` is defined in `cusf_sidechain_types` and only carries a 20 byte
+/// `main_address`, which is paid to as P2PKH. Other destinations are carried next to the
+/// transaction, in [`AuthorizedTransaction::withdrawal_destinations`], and take precedence over
+/// `main_address`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum WithdrawalDestination {
+    P2pkh([u8; 20]),
+    P2sh([u8; 20]),
+    P2wpkh([u8; 20]),
+    P2wsh([u8; 32]),
+    /// Tweaked x-only output key.
+    P2tr([u8; 32]),
+}
+
+impl WithdrawalDestination {
+    pub fn script_pubkey(&self) -> ScriptBuf {
+        match self {
+            Self::P2pkh(pubkey_hash) => Builder::new()
+                .push_opcode(OP_DUP)
+                .push_opcode(OP_HASH160)
+                .push_slice(pubkey_hash)
+                .push_opcode(OP_EQUALVERIFY)
+                .push_opcode(OP_CHECKSIG)
+                .into_script(),
+            Self::P2sh(script_hash) => Builder::new()
+                .push_opcode(OP_HASH160)
+                .push_slice(script_hash)
+                .push_opcode(OP_EQUAL)
+                .into_script(),
+            Self::P2wpkh(pubkey_hash) => Builder::new()
+                .push_opcode(OP_0)
+                .push_slice(pubkey_hash)
+                .into_script(),
+            Self::P2wsh(script_hash) => Builder::new()
+                .push_opcode(OP_0)
+                .push_slice(script_hash)
+                .into_script(),
+            Self::P2tr(output_key) => Builder::new()
+                .push_opcode(OP_PUSHNUM_1)
+                .push_slice(output_key)
+                .into_script(),
+        }
+    }
+
+    /// Hashes can be anything, but a taproot output key that isn't on the curve can never be
+    /// spent, so paying to it would burn the withdrawal.
+    fn is_valid(&self) -> bool {
+        match self {
+            Self::P2tr(output_key) => XOnlyPublicKey::from_slice(output_key).is_ok(),
+            _ => true,
+        }
+    }
+}
+
+/// Checks that a transaction either has no withdrawal destinations, or exactly one valid
+/// destination per withdrawal output.
+pub fn check_withdrawal_destinations(
+    transaction: &AuthorizedTransaction,
+) -> Result<(), TransactionError> {
+    let withdrawals = transaction
+        .transaction
+        .outputs
+        .iter()
+        .filter(|output| matches!(output, Output::Withdrawal { .. }))
+        .count();
+    let destinations = &transaction.withdrawal_destinations;
+    if !destinations.is_empty() && destinations.len() != withdrawals {
+        return Err(TransactionError::WrongWithdrawalDestinationCount {
+            withdrawals,
+            destinations: destinations.len(),
+        });
+    }
+    for (index, destination) in destinations.iter().enumerate() {
+        if !destination.is_valid() {
+            return Err(TransactionError::InvalidWithdrawalDestination(index));
+        }
+    }
+    Ok(())
+}